@@ -0,0 +1,61 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use libactionkv::ActionKV;
+
+const RECORDS: u64 = 10_000;
+const VALUE: &[u8] = &[0x5a; 100];
+
+fn key(i: u64) -> Vec<u8> {
+    format!("user{:010}", i).into_bytes()
+}
+
+/// A loaded store holding `RECORDS` keys.
+fn filled_store(dir: &tempfile::TempDir) -> ActionKV {
+    let mut store = ActionKV::open(&dir.path().join("bench.akv")).unwrap();
+    store.load().unwrap();
+    for i in 0..RECORDS {
+        store.insert(&key(i), VALUE).unwrap();
+    }
+    store
+}
+
+fn insert(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = ActionKV::open(&dir.path().join("insert.akv")).unwrap();
+    store.load().unwrap();
+    let mut i = 0;
+    c.bench_function("insert", |b| b.iter(|| {
+        store.insert(&key(i), VALUE).unwrap();
+        i += 1;
+    }));
+}
+
+fn get(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = filled_store(&dir);
+    let mut i = 0;
+    c.bench_function("get", |b| b.iter(|| {
+        store.get(&key(i % RECORDS)).unwrap();
+        i += 7919;
+    }));
+}
+
+fn load(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bench.akv");
+    drop(filled_store(&dir));
+    c.bench_function("load", |b| b.iter_batched(
+        || ActionKV::open(&path).unwrap(),
+        |mut store| store.load().unwrap(),
+        BatchSize::PerIteration,
+    ));
+}
+
+fn find(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = filled_store(&dir);
+    let target = key(RECORDS / 2);
+    c.bench_function("find", |b| b.iter(|| store.find(&target).unwrap()));
+}
+
+criterion_group!(benches, insert, get, load, find);
+criterion_main!(benches);
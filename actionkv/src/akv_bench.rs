@@ -0,0 +1,268 @@
+use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use libactionkv::{ActionKV, StoreOptions};
+
+#[cfg(target_os="windows")]
+const USAGE: &str = r#"
+Usage:
+    akv_bench.exe FILE [OPTIONS]
+
+Options:
+    --workload a|b|c         YCSB mix: 50%, 95% or 100% reads [default: a]
+    --read-ratio R           fraction of operations that are reads, 0 to 1
+    --distribution D         uniform or zipfian [default: zipfian]
+    --records N              keys inserted before the run [default: 10000]
+    --operations N           operations in the run [default: 100000]
+    --value-size BYTES       [default: 100]
+    --seed N                 [default: 0]
+    --cache-bytes BYTES      cache up to BYTES of values in memory
+"#;
+
+#[cfg(not(target_os="windows"))]
+const USAGE: &str = r#"
+Usage:
+    akv_bench FILE [OPTIONS]
+
+Options:
+    --workload a|b|c         YCSB mix: 50%, 95% or 100% reads [default: a]
+    --read-ratio R           fraction of operations that are reads, 0 to 1
+    --distribution D         uniform or zipfian [default: zipfian]
+    --records N              keys inserted before the run [default: 10000]
+    --operations N           operations in the run [default: 100000]
+    --value-size BYTES       [default: 100]
+    --seed N                 [default: 0]
+    --cache-bytes BYTES      cache up to BYTES of values in memory
+"#;
+
+// The skew YCSB uses for its zipfian workloads
+const ZIPFIAN_CONSTANT: f64 = 0.99;
+
+#[derive(Debug)]
+struct Config {
+    read_ratio: f64,
+    zipfian: bool,
+    records: u64,
+    operations: u64,
+    value_size: usize,
+    seed: u64,
+    cache_bytes: Option<usize>,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Option<Config> {
+        let mut config = Config {
+            read_ratio: 0.5,
+            zipfian: true,
+            records: 10_000,
+            operations: 100_000,
+            value_size: 100,
+            seed: 0,
+            cache_bytes: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next()?;
+            match flag.as_str() {
+                "--workload" => {
+                    config.read_ratio = match value.as_str() {
+                        "a" => 0.5,
+                        "b" => 0.95,
+                        "c" => 1.0,
+                        _ => return None,
+                    }
+                },
+                "--read-ratio" => {
+                    config.read_ratio = value.parse().ok().filter(|r| (0.0..=1.0).contains(r))?;
+                },
+                "--distribution" => {
+                    config.zipfian = match value.as_str() {
+                        "uniform" => false,
+                        "zipfian" => true,
+                        _ => return None,
+                    }
+                },
+                "--records" => config.records = value.parse().ok().filter(|&n| n > 0)?,
+                "--operations" => config.operations = value.parse().ok()?,
+                "--value-size" => config.value_size = value.parse().ok()?,
+                "--seed" => config.seed = value.parse().ok()?,
+                "--cache-bytes" => config.cache_bytes = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+
+        Some(config)
+    }
+}
+
+/// Picks items from `0..n` so that item `i` is chosen in proportion to
+/// `1 / (i + 1)^theta`, following Gray et al., "Quickly Generating
+/// Billion-Record Synthetic Databases".
+struct Zipfian {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zeta_n: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(n: u64, theta: f64) -> Zipfian {
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zeta_n = zeta(n);
+        let zeta_2 = zeta(2);
+        let alpha = 1.0 / (1.0 - theta);
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta_2 / zeta_n);
+        Zipfian { n, theta, alpha, zeta_n, eta }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zeta_n;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let item = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        item.min(self.n - 1)
+    }
+}
+
+/// Latencies of one kind of operation.
+#[derive(Default)]
+struct Latencies {
+    nanos: Vec<u64>,
+}
+
+impl Latencies {
+    fn record(&mut self, elapsed: Duration) {
+        self.nanos.push(elapsed.as_nanos() as u64);
+    }
+
+    /// The latency that `percentile` percent of operations beat, in
+    /// microseconds.
+    fn percentile(&self, percentile: f64) -> f64 {
+        if self.nanos.is_empty() {
+            return 0.0;
+        }
+        let rank = (percentile / 100.0 * self.nanos.len() as f64).ceil() as usize;
+        self.nanos[rank.clamp(1, self.nanos.len()) - 1] as f64 / 1000.0
+    }
+
+    fn report(&mut self, name: &str) {
+        self.nanos.sort_unstable();
+        println!(
+            "{:<8} {:>10} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+            name,
+            self.nanos.len(),
+            self.percentile(50.0),
+            self.percentile(95.0),
+            self.percentile(99.0),
+            self.percentile(99.9),
+            self.percentile(100.0),
+        );
+    }
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("user{:010}", i).into_bytes()
+}
+
+fn random_value<R: RngCore>(rng: &mut R, len: usize) -> Vec<u8> {
+    let mut value = vec![0; len];
+    rng.fill_bytes(&mut value);
+    value
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let file_name = args.get(1).expect(USAGE);
+    let config = Config::parse(&args[2..]).expect(USAGE);
+
+    let path = std::path::Path::new(&file_name);
+    let options = StoreOptions { cache_bytes: config.cache_bytes, ..StoreOptions::default() };
+    let mut store = ActionKV::open_with(path, &options).expect("Unable to open file");
+    store.load().expect("Unable to load data from store");
+    let mut rng = StdRng::seed_from_u64(config.seed);
+
+    let started = Instant::now();
+    for i in 0..config.records {
+        let value = random_value(&mut rng, config.value_size);
+        store.insert(&key(i), &value).expect("Failed to insert");
+    }
+    let elapsed = started.elapsed();
+    println!(
+        "load: {} records in {:.2?} ({:.0} ops/s)",
+        config.records,
+        elapsed,
+        config.records as f64 / elapsed.as_secs_f64()
+    );
+
+    let zipfian = Zipfian::new(config.records, ZIPFIAN_CONSTANT);
+    let mut reads = Latencies::default();
+    let mut writes = Latencies::default();
+    let started = Instant::now();
+    for _ in 0..config.operations {
+        let i = if config.zipfian {
+            zipfian.sample(&mut rng)
+        } else {
+            rng.gen_range(0..config.records)
+        };
+        let key = key(i);
+        if rng.gen_bool(config.read_ratio) {
+            let op = Instant::now();
+            store.get(&key).expect("Failed to get");
+            reads.record(op.elapsed());
+        } else {
+            let value = random_value(&mut rng, config.value_size);
+            let op = Instant::now();
+            store.update(&key, &value).expect("Failed to update");
+            writes.record(op.elapsed());
+        }
+    }
+    let elapsed = started.elapsed();
+    println!(
+        "run:  {} operations in {:.2?} ({:.0} ops/s)",
+        config.operations,
+        elapsed,
+        config.operations as f64 / elapsed.as_secs_f64()
+    );
+
+    println!();
+    println!("{:<8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}", "op", "count", "p50 us", "p95 us", "p99 us", "p99.9 us", "max us");
+    reads.report("read");
+    writes.report("update");
+
+    if let Some(cache) = store.stats().expect("Failed to read stats").cache {
+        println!();
+        println!("cache: {} hits, {} misses", cache.hits, cache.misses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zipfian_favours_low_items() {
+        let zipfian = Zipfian::new(1000, ZIPFIAN_CONSTANT);
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples: Vec<_> = (0..10_000).map(|_| zipfian.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|&i| i < 1000));
+        let head = samples.iter().filter(|&&i| i < 10).count();
+        let tail = samples.iter().filter(|&&i| i >= 990).count();
+        assert!(head > tail * 10, "head {} tail {}", head, tail);
+    }
+
+    #[test]
+    fn percentiles_pick_by_rank() {
+        let mut latencies = Latencies { nanos: (1..=100).map(|us| us * 1000).collect() };
+        latencies.nanos.sort_unstable();
+        assert_eq!(latencies.percentile(50.0), 50.0);
+        assert_eq!(latencies.percentile(99.0), 99.0);
+        assert_eq!(latencies.percentile(100.0), 100.0);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+use libactionkv::raft::{Command, Envelope, NodeId, Progress, Proposal, RaftNode, RaftOptions, ReadId};
+use libactionkv::{http, ByteString};
+
+#[cfg(target_os="windows")]
+const USAGE: &str = r#"
+Usage:
+    akv_cluster.exe FILE ID ADDR [PEER_ID=PEER_ADDR ...]
+
+Runs node ID of a Raft cluster, keeping its data in FILE and listening on
+ADDR. Every node has to be started with the same set of ids. The leader
+serves:
+    GET /kv/{key}, PUT /kv/{key}, DELETE /kv/{key}
+    GET /health
+Other nodes answer 503 with the leader's address.
+"#;
+
+#[cfg(not(target_os="windows"))]
+const USAGE: &str = r#"
+Usage:
+    akv_cluster FILE ID ADDR [PEER_ID=PEER_ADDR ...]
+
+Runs node ID of a Raft cluster, keeping its data in FILE and listening on
+ADDR. Every node has to be started with the same set of ids. The leader
+serves:
+    GET /kv/{key}, PUT /kv/{key}, DELETE /kv/{key}
+    GET /health
+Other nodes answer 503 with the leader's address.
+"#;
+
+const TICK: Duration = Duration::from_millis(50);
+// How long a client waits for its write or read before giving up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+
+enum Waiting {
+    Write(Proposal),
+    Read(ReadId, ByteString),
+}
+
+struct PendingRequest {
+    request: Request,
+    waiting: Waiting,
+    started: Instant,
+}
+
+/// Sends messages to one peer, in order, dropping any that can't be
+/// delivered. Raft copes with lost messages.
+fn spawn_sender(addr: SocketAddr) -> mpsc::Sender<Envelope> {
+    let (sender, envelopes) = mpsc::channel::<Envelope>();
+    thread::spawn(move || {
+        for envelope in envelopes {
+            let body = bincode::serialize(&envelope).unwrap();
+            let _ = post(addr, "/raft", &body);
+        }
+    });
+    sender
+}
+
+fn post(addr: SocketAddr, path: &str, body: &[u8]) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+        path, addr, body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.read_to_end(&mut Vec::new())?;
+    Ok(())
+}
+
+fn resolve(addr: &str) -> SocketAddr {
+    addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()).expect(USAGE)
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    Response::from_data(body.to_string()).with_header(content_type).with_status_code(status)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let file_name = args.get(1).expect(USAGE);
+    let id: NodeId = args.get(2).and_then(|id| id.parse().ok()).expect(USAGE);
+    let addr = args.get(3).expect(USAGE);
+
+    let mut peers: HashMap<NodeId, (String, mpsc::Sender<Envelope>)> = HashMap::new();
+    for peer in &args[4..] {
+        let (peer_id, peer_addr) = peer.split_once('=').expect(USAGE);
+        let peer_id = peer_id.parse().expect(USAGE);
+        peers.insert(peer_id, (peer_addr.to_string(), spawn_sender(resolve(peer_addr))));
+    }
+    let mut ids: Vec<NodeId> = peers.keys().copied().collect();
+    ids.push(id);
+
+    let path = std::path::Path::new(&file_name);
+    let options = RaftOptions { seed: rand::random(), ..RaftOptions::default() };
+    let mut node = RaftNode::open(id, &ids, path, options).expect("Unable to open node");
+    let server = Server::http(addr).expect("Unable to listen");
+    println!("Node {} serving {} on http://{}", id, file_name, server.server_addr());
+
+    let mut pending: Vec<PendingRequest> = Vec::new();
+    let mut last_tick = Instant::now();
+    loop {
+        let wait = TICK.saturating_sub(last_tick.elapsed());
+        if let Some(request) = server.recv_timeout(wait).expect("Unable to receive requests") {
+            if let Some(waiting) = handle(&mut node, &peers, request) {
+                pending.push(waiting);
+            }
+        }
+        if last_tick.elapsed() >= TICK {
+            node.tick().expect("Unable to write to the store");
+            last_tick = Instant::now();
+        }
+
+        for envelope in node.take_messages().expect("Unable to sync the Raft log") {
+            if let Some((_, sender)) = peers.get(&envelope.to) {
+                let _ = sender.send(envelope);
+            }
+        }
+        pending = pending.into_iter().filter_map(|p| finish(&mut node, p)).collect();
+    }
+}
+
+/// Answers `request`, or returns it if it has to wait for the cluster.
+fn handle(node: &mut RaftNode, peers: &HashMap<NodeId, (String, mpsc::Sender<Envelope>)>, mut request: Request) -> Option<PendingRequest> {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+
+    if (&method, url.as_str()) == (&Method::Post, "/raft") {
+        let mut body = Vec::new();
+        let envelope = request.as_reader().read_to_end(&mut body).ok()
+            .and_then(|_| bincode::deserialize::<Envelope>(&body).ok());
+        let status = match envelope {
+            Some(envelope) => {
+                node.step(envelope.from, envelope.message).expect("Unable to write to the store");
+                204
+            },
+            None => 400,
+        };
+        let _ = request.respond(Response::empty(status));
+        return None;
+    }
+    if (&method, url.as_str()) == (&Method::Get, "/health") {
+        let body = json!({ "status": "ok", "id": node.id(), "term": node.term(), "leader": node.leader() });
+        let _ = request.respond(json_response(200, body));
+        return None;
+    }
+
+    let key = match url.strip_prefix("/kv/").map(http::percent_decode) {
+        Some(Some(key)) => key,
+        Some(None) => {
+            let _ = request.respond(json_response(400, json!({ "error": "malformed percent-encoding in key" })));
+            return None;
+        },
+        None => {
+            let _ = request.respond(json_response(404, json!({ "error": "not found" })));
+            return None;
+        },
+    };
+
+    let waiting = match method {
+        Method::Get => node.read_index().map(|read| Waiting::Read(read, key)),
+        Method::Put | Method::Delete => {
+            let mut value = Vec::new();
+            if method == Method::Put {
+                if let Err(err) = request.as_reader().read_to_end(&mut value) {
+                    let _ = request.respond(json_response(400, json!({ "error": err.to_string() })));
+                    return None;
+                }
+            }
+            node.propose(Command::Insert { key, value }).map(Waiting::Write)
+        },
+        _ => {
+            let _ = request.respond(json_response(405, json!({ "error": "method not allowed" })));
+            return None;
+        },
+    };
+
+    match waiting {
+        Ok(waiting) => Some(PendingRequest { request, waiting, started: Instant::now() }),
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            let _ = request.respond(json_response(400, json!({ "error": err.to_string() })));
+            None
+        },
+        Err(err) => {
+            let leader = node.leader().and_then(|leader| peers.get(&leader)).map(|(addr, _)| addr.clone());
+            let _ = request.respond(json_response(503, json!({ "error": err.to_string(), "leader": leader })));
+            None
+        },
+    }
+}
+
+/// Responds to a waiting request once the cluster is done with it.
+fn finish(node: &mut RaftNode, pending: PendingRequest) -> Option<PendingRequest> {
+    let progress = match &pending.waiting {
+        Waiting::Write(proposal) => node.proposal_progress(proposal),
+        Waiting::Read(read, _) => node.read_progress(*read),
+    };
+
+    let response = match progress {
+        Progress::Pending if pending.started.elapsed() < REQUEST_TIMEOUT => return Some(pending),
+        Progress::Pending => json_response(504, json!({ "error": "timed out waiting for the cluster" })),
+        Progress::Failed => json_response(503, json!({ "error": "the leader stepped down" })),
+        Progress::Done => match &pending.waiting {
+            Waiting::Write(_) => Response::from_data(Vec::new()).with_status_code(204),
+            Waiting::Read(_, key) => match node.store().get(key) {
+                Ok(Some(value)) if !value.is_empty() => Response::from_data(value),
+                Ok(_) => json_response(404, json!({ "error": "key not found" })),
+                Err(err) => json_response(500, json!({ "error": err.to_string() })),
+            },
+        },
+    };
+    let _ = pending.request.respond(response);
+    None
+}
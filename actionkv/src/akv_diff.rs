@@ -0,0 +1,44 @@
+use libactionkv::diff::{diff, Change};
+use libactionkv::{shell, ActionKV};
+
+#[cfg(target_os="windows")]
+const USAGE: &str = r#"
+Usage:
+    akv_diff.exe A B
+
+Lists the keys added (+), removed (-) or changed (~) going from store A to
+store B. Keys in a column family follow the column family's name.
+"#;
+
+#[cfg(not(target_os="windows"))]
+const USAGE: &str = r#"
+Usage:
+    akv_diff A B
+
+Lists the keys added (+), removed (-) or changed (~) going from store A to
+store B. Keys in a column family follow the column family's name.
+"#;
+
+fn open(file_name: &str) -> ActionKV {
+    let mut store = ActionKV::open_read_only(std::path::Path::new(file_name)).expect("Unable to open file");
+    store.load().expect("Unable to load data from store");
+    store
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut a = open(args.get(1).expect(USAGE));
+    let mut b = open(args.get(2).expect(USAGE));
+
+    for change in diff(&mut a, &mut b).expect("Unable to compare stores") {
+        let mark = match change {
+            Change::Added { .. } => '+',
+            Change::Removed { .. } => '-',
+            Change::Changed { .. } => '~',
+        };
+        match change.cf() {
+            Some(cf) => println!("{} {} \"{}\"", mark, cf, shell::escape(change.key())),
+            None => println!("{} \"{}\"", mark, shell::escape(change.key())),
+        }
+    }
+}
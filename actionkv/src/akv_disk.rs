@@ -1,68 +1,189 @@
+mod cli;
+
 use std::collections::HashMap;
-use libactionkv::{ActionKV, ByteStr, ByteString};
+use std::fs;
+use std::path::Path;
+use cli::{run_cf_command, take_flag, ValueIo};
+use libactionkv::{ActionKV, BloomFilter, ByteStr, ByteString, RECORD_HEADER_LEN};
 
 #[cfg(target_os="windows")]
 const USAGE: &str = r#"
 Usage:
-    akv_disk.exe FILE get KEY
-    akv_disk.exe FILE delete KEY
-    akv_disk.exe FILE insert KEY VALUE
-    akv_disk.exe FILE update KEY VALUE
+    akv_disk.exe FILE [--cf NAME] get KEY
+    akv_disk.exe FILE [--cf NAME] delete KEY
+    akv_disk.exe FILE [--cf NAME] insert KEY VALUE
+    akv_disk.exe FILE [--cf NAME] update KEY VALUE
+    akv_disk.exe FILE create-cf NAME
+    akv_disk.exe FILE drop-cf NAME
+    akv_disk.exe FILE list-cf
+
+Options:
+    --key-encoding E      how KEY is written and printed: utf8, hex or base64
+                          [default: utf8]
+    --value-encoding E    the same for VALUE
+    --value-file PATH     insert the contents of PATH, or get into PATH
+    --value-stdin         insert what is read from stdin
 "#;
 
 #[cfg(not(target_os="windows"))]
 const USAGE: &str = r#"
 Usage:
-    akv_disk FILE get KEY
-    akv_disk FILE delete KEY
-    akv_disk FILE insert KEY VALUE
-    akv_disk FILE update KEY VALUE
+    akv_disk FILE [--cf NAME] get KEY
+    akv_disk FILE [--cf NAME] delete KEY
+    akv_disk FILE [--cf NAME] insert KEY VALUE
+    akv_disk FILE [--cf NAME] update KEY VALUE
+    akv_disk FILE create-cf NAME
+    akv_disk FILE drop-cf NAME
+    akv_disk FILE list-cf
+
+Options:
+    --key-encoding E      how KEY is written and printed: utf8, hex or base64
+                          [default: utf8]
+    --value-encoding E    the same for VALUE
+    --value-file PATH     insert the contents of PATH, or get into PATH
+    --value-stdin         insert what is read from stdin
 "#;
 
-fn store_index_on_disk(store: &mut ActionKV, index_key: &ByteStr) {
-    store.index.remove(index_key);
-    let index_as_bytes = bincode::serialize(&store.index).unwrap();
-    store.index = HashMap::new();
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Saves a snapshot of the index, and a bloom filter of its keys that lets
+/// `get` turn away missing keys without decoding the index.
+///
+/// The bloom filter is written first and the snapshot records where, so
+/// that `saved_index` can tell whether the two are still the last records
+/// in the file.
+fn store_index_on_disk(store: &mut ActionKV, index_key: &ByteStr, bloom_key: &ByteStr) {
+    let mut index = store.index.clone();
+    index.remove(index_key);
+    index.remove(bloom_key);
+    let mut bloom = BloomFilter::new(index.len(), BLOOM_FALSE_POSITIVE_RATE);
+    for key in index.keys() {
+        bloom.insert(key);
+    }
+
+    let bloom_as_bytes = bincode::serialize(&bloom).unwrap();
+    store.insert(bloom_key, &bloom_as_bytes).unwrap();
+    index.insert(bloom_key.to_vec(), store.index[bloom_key]);
+    let index_as_bytes = bincode::serialize(&index).unwrap();
     store.insert(index_key, &index_as_bytes).unwrap();
 }
 
+/// The saved index and bloom filter, if nothing has been written since they
+/// were saved. Only reads the file.
+///
+/// Writes by other tools, compaction and migration all leave the snapshot
+/// somewhere other than the end of the file, and its offsets possibly wrong.
+/// `get` then uses the index `load` built until a write saves a new one.
+fn saved_index(store: &mut ActionKV, path: &Path, index_key: &ByteStr, bloom_key: &ByteStr) -> Option<(HashMap<ByteString, u64>, BloomFilter)> {
+    let index_position = *store.index.get(index_key)?;
+    let record = store.get_at(index_position).ok()?;
+    let record_end = index_position + RECORD_HEADER_LEN + (record.key.len() + record.value.len()) as u64;
+    if record_end != fs::metadata(path).ok()?.len() {
+        return None;
+    }
+
+    let index: HashMap<ByteString, u64> = bincode::deserialize(&record.value).ok()?;
+    let bloom_position = *index.get(bloom_key)?;
+    if store.index.get(bloom_key) != Some(&bloom_position) {
+        return None;
+    }
+    let bloom = bincode::deserialize(&store.get_at(bloom_position).ok()?.value).ok()?;
+    Some((index, bloom))
+}
+
 fn main() {
     const INDEX_KEY: &ByteStr = b"+index";
-    let args: Vec<String> = std::env::args().collect();
-    let file_name = args.get(1).expect(&USAGE);
-    let action = args.get(2).expect(&USAGE).as_ref();
-    let key = args.get(3).expect(&USAGE).as_bytes();
-    let value = args.get(4);
+    const BLOOM_KEY: &ByteStr = b"+bloom";
+    let mut args: Vec<String> = std::env::args().collect();
+    let cf = take_flag(&mut args, "--cf", USAGE);
+    let values = ValueIo::take(&mut args, USAGE);
+    let file_name = args.get(1).expect(USAGE);
+    let action = args.get(2).expect(USAGE).as_ref();
 
-    let path = std::path::Path::new(&file_name);
+    let path = Path::new(&file_name);
     let mut store = ActionKV::open(path).expect("Unable to open file");
     store.load().expect("Unable to load data from store");
+    let dropped = store.metrics().dropped_tail_bytes();
+    if dropped > 0 {
+        eprintln!("Dropped {} bytes of damaged records from the end of {}", dropped, file_name);
+    }
+    if run_cf_command(&mut store, cf.as_deref(), action, &args, &values) {
+        // Any write leaves the saved index behind the end of the file
+        if !matches!(action, "get" | "list-cf") {
+            store_index_on_disk(&mut store, INDEX_KEY, BLOOM_KEY);
+        }
+        return;
+    }
+
+    let key = values.key(args.get(3));
+    let key = key.as_slice();
+    let value = args.get(4);
 
     match action {
         "get" => {
-            let index_as_bytes = store.get(&INDEX_KEY)
-                .unwrap().unwrap();
-            let index_decoded = bincode::deserialize(index_as_bytes.as_slice());
-            let index: HashMap<ByteString, u64> = index_decoded.unwrap();
-
-            match index.get(key) {
-                None => eprintln!("{:?} not found", key),
-                Some(&i) => {
-                    let kv = store.get_at(i).unwrap();
-                    println!("{}", String::from_utf8_lossy(kv.value.as_slice()))
-                }
+            let position = match saved_index(&mut store, path, INDEX_KEY, BLOOM_KEY) {
+                Some((_, bloom)) if !bloom.may_contain(key) => None,
+                Some((index, _)) => index.get(key).copied(),
+                None => store.index.get(key).copied(),
+            };
+            // Deletes are records with empty values
+            match position.map(|i| store.get_at(i).unwrap().value) {
+                Some(value) if !value.is_empty() => values.print_value(&value),
+                _ => values.print_not_found(key),
             }
         },
-        "delete" => store.delete(key).unwrap(),
+        "delete" => {
+            store.delete(key).unwrap();
+            store_index_on_disk(&mut store, INDEX_KEY, BLOOM_KEY);
+        },
         "insert" => {
-            let value = value.expect(&USAGE).as_bytes();
-            store.insert(key, value).unwrap();
-            store_index_on_disk(&mut store, INDEX_KEY);
+            let value = values.value(value);
+            store.insert(key, &value).unwrap();
+            store_index_on_disk(&mut store, INDEX_KEY, BLOOM_KEY);
         },
         "update" => {
-            let value = value.expect(&USAGE).as_bytes();
-            store.update(key, value).unwrap();
+            let value = values.value(value);
+            store.update(key, &value).unwrap();
+            store_index_on_disk(&mut store, INDEX_KEY, BLOOM_KEY);
         },
-        _ => eprintln!("{}", &USAGE),
+        _ => eprintln!("{}", USAGE),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_index_is_only_used_while_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"old", b"1").unwrap();
+        store.insert(b"old", b"2").unwrap();
+        store.insert(b"k", b"v").unwrap();
+        store_index_on_disk(&mut store, b"+index", b"+bloom");
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        let (index, bloom) = saved_index(&mut store, &path, b"+index", b"+bloom").unwrap();
+        assert_eq!(store.get_at(index[&b"k"[..]]).unwrap().value, b"v");
+        assert!(bloom.may_contain(b"k"));
+
+        // Compaction drops the first "old" record, moving the rest
+        store.compact().unwrap();
+        store.load().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        assert!(saved_index(&mut store, &path, b"+index", b"+bloom").is_none());
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        store_index_on_disk(&mut store, b"+index", b"+bloom");
+        let (index, _) = saved_index(&mut store, &path, b"+index", b"+bloom").unwrap();
+        assert_eq!(store.get_at(index[&b"k"[..]]).unwrap().value, b"v");
+
+        store.insert(b"k", b"w").unwrap();
+        assert!(saved_index(&mut store, &path, b"+index", b"+bloom").is_none());
+    }
+}
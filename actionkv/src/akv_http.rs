@@ -0,0 +1,45 @@
+use libactionkv::{http, ActionKV};
+
+#[cfg(target_os="windows")]
+const USAGE: &str = r#"
+Usage:
+    akv_http.exe FILE [ADDR]
+
+Serves FILE over HTTP on ADDR, 127.0.0.1:8080 by default:
+    GET /kv/{key}, PUT /kv/{key}, DELETE /kv/{key}
+    GET /kv?prefix=PREFIX
+    GET /stats
+    GET /metrics
+    GET /health
+"#;
+
+#[cfg(not(target_os="windows"))]
+const USAGE: &str = r#"
+Usage:
+    akv_http FILE [ADDR]
+
+Serves FILE over HTTP on ADDR, 127.0.0.1:8080 by default:
+    GET /kv/{key}, PUT /kv/{key}, DELETE /kv/{key}
+    GET /kv?prefix=PREFIX
+    GET /stats
+    GET /metrics
+    GET /health
+"#;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let file_name = args.get(1).expect(USAGE);
+    let addr = args.get(2).map(String::as_str).unwrap_or("127.0.0.1:8080");
+
+    let path = std::path::Path::new(&file_name);
+    let mut store = ActionKV::open(path).expect("Unable to open file");
+    store.load().expect("Unable to load data from store");
+    let dropped = store.metrics().dropped_tail_bytes();
+    if dropped > 0 {
+        eprintln!("Dropped {} bytes of damaged records from the end of {}", dropped, file_name);
+    }
+
+    let server = tiny_http::Server::http(addr).expect("Unable to listen");
+    println!("Serving {} on http://{}", file_name, server.server_addr());
+    http::serve(&server, &mut store);
+}
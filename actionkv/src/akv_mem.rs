@@ -1,48 +1,188 @@
-use libactionkv::ActionKV;
+mod cli;
+
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+use cli::{run_cf_command, take_flag, ValueIo};
+use libactionkv::{backup, migrate, shell, ActionKV, RestorePoint};
 
 #[cfg(target_os="windows")]
 const USAGE: &str = r#"
 Usage:
-    akv_mem.exe FILE get KEY
-    akv_mem.exe FILE delete KEY
-    akv_mem.exe FILE insert KEY VALUE
-    akv_mem.exe FILE update KEY VALUE
+    akv_mem.exe FILE [--cf NAME] get KEY
+    akv_mem.exe FILE [--cf NAME] delete KEY
+    akv_mem.exe FILE [--cf NAME] insert KEY VALUE
+    akv_mem.exe FILE [--cf NAME] update KEY VALUE
+    akv_mem.exe FILE create-cf NAME
+    akv_mem.exe FILE drop-cf NAME
+    akv_mem.exe FILE list-cf
+    akv_mem.exe FILE history KEY
+    akv_mem.exe FILE get-stream KEY > VALUE
+    akv_mem.exe FILE insert-stream KEY [LEN] < VALUE
+    akv_mem.exe FILE stats
+    akv_mem.exe FILE backup DEST
+    akv_mem.exe FILE restore DEST --until-offset N
+    akv_mem.exe FILE restore DEST --until-time TIME
+    akv_mem.exe FILE migrate
+    akv_mem.exe FILE shell
+
+Options:
+    --key-encoding E      how KEY is written and printed: utf8, hex or base64
+                          [default: utf8]
+    --value-encoding E    the same for VALUE
+    --value-file PATH     insert the contents of PATH, or get into PATH
+    --value-stdin         insert what is read from stdin
+
+backup copies the store up to its last whole record, and works while another
+process is writing to it.
 "#;
 
 #[cfg(not(target_os="windows"))]
 const USAGE: &str = r#"
 Usage:
-    akv_mem FILE get KEY
-    akv_mem FILE delete KEY
-    akv_mem FILE insert KEY VALUE
-    akv_mem FILE update KEY VALUE
+    akv_mem FILE [--cf NAME] get KEY
+    akv_mem FILE [--cf NAME] delete KEY
+    akv_mem FILE [--cf NAME] insert KEY VALUE
+    akv_mem FILE [--cf NAME] update KEY VALUE
+    akv_mem FILE create-cf NAME
+    akv_mem FILE drop-cf NAME
+    akv_mem FILE list-cf
+    akv_mem FILE history KEY
+    akv_mem FILE get-stream KEY > VALUE
+    akv_mem FILE insert-stream KEY [LEN] < VALUE
+    akv_mem FILE stats
+    akv_mem FILE backup DEST
+    akv_mem FILE restore DEST --until-offset N
+    akv_mem FILE restore DEST --until-time TIME
+    akv_mem FILE migrate
+    akv_mem FILE shell
+
+Options:
+    --key-encoding E      how KEY is written and printed: utf8, hex or base64
+                          [default: utf8]
+    --value-encoding E    the same for VALUE
+    --value-file PATH     insert the contents of PATH, or get into PATH
+    --value-stdin         insert what is read from stdin
+
+backup copies the store up to its last whole record, and works while another
+process is writing to it.
 "#;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let file_name = args.get(1).expect(&USAGE);
-    let action = args.get(2).expect(&USAGE).as_ref();
-    let key = args.get(3).expect(&USAGE).as_bytes();
-    let value = args.get(4);
+    let mut args: Vec<String> = std::env::args().collect();
+    let cf = take_flag(&mut args, "--cf", USAGE);
+    let values = ValueIo::take(&mut args, USAGE);
+    let file_name = args.get(1).expect(USAGE);
+    let action = args.get(2).expect(USAGE).as_ref();
 
     let path = std::path::Path::new(&file_name);
-    let mut store = ActionKV::open(path).expect("Unable to open file");
+    if action == "migrate" {
+        let (from, to) = migrate::upgrade(path).expect("Unable to migrate store");
+        if from == to {
+            println!("{} is already at format version {}", file_name, to);
+        } else {
+            println!("Migrated {} from format version {} to {}", file_name, from, to);
+        }
+        return;
+    }
+    if action == "backup" {
+        let dest = std::path::Path::new(args.get(3).expect(USAGE));
+        let len = backup::backup_live(path, dest).expect("Unable to back up store");
+        println!("Backed up {} bytes of {} to {}", len, file_name, dest.display());
+        return;
+    }
+    if action == "restore" {
+        let dest = std::path::Path::new(args.get(3).expect(USAGE));
+        let until = parse_restore_point(args.get(4), args.get(5)).expect(USAGE);
+        let len = backup::restore(path, dest, until).expect("Unable to restore store");
+        println!("Restored {} bytes of {} to {}", len, file_name, dest.display());
+        return;
+    }
+
+    let mut store = match action {
+        "get" | "get-stream" | "history" | "stats" | "list-cf" => ActionKV::open_read_only(path),
+        _ => ActionKV::open(path),
+    }.expect("Unable to open file");
     store.load().expect("Unable to load data from store");
+    let dropped = store.metrics().dropped_tail_bytes();
+    if dropped > 0 {
+        eprintln!("Dropped {} bytes of damaged records from the end of {}", dropped, file_name);
+    }
+
+    match action {
+        "shell" => {
+            if let Err(err) = shell::run(&mut store) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        },
+        "stats" => {
+            print!("{}", store.stats().expect("Unable to read store statistics"));
+            return;
+        },
+        _ => {},
+    }
+    if run_cf_command(&mut store, cf.as_deref(), action, &args, &values) {
+        return;
+    }
+
+    let key = values.key(args.get(3));
+    let key = key.as_slice();
+    let value = args.get(4);
 
     match action {
         "get" => match store.get(key).expect("Failed to get") {
-            None => eprintln!("{:?} not found", key),
-            Some(value) => println!("{}", String::from_utf8_lossy(value.as_slice()))
+            None => values.print_not_found(key),
+            Some(value) => values.print_value(&value),
         },
         "delete" => store.delete(key).unwrap(),
         "insert" => {
-            let value = value.expect(&USAGE).as_bytes();
-            store.insert(key, value).unwrap();
+            let value = values.value(value);
+            store.insert(key, &value).unwrap();
         },
         "update" => {
-            let value = value.expect(&USAGE).as_bytes();
-            store.update(key, value).unwrap();
+            let value = values.value(value);
+            store.update(key, &value).unwrap();
+        },
+        "history" => {
+            for (offset, value) in store.history(key).expect("Failed to read history") {
+                println!("{:>12}  {}", offset, values.encode_value(&value));
+            }
+        },
+        "get-stream" => match store.get_reader(key).expect("Failed to get") {
+            None => values.print_not_found(key),
+            Some(mut reader) => {
+                io::copy(&mut reader, &mut io::stdout().lock()).expect("Failed to write value");
+            }
+        },
+        "insert-stream" => {
+            // Without a length the value can be any size, so it is chunked
+            let stdin = io::stdin().lock();
+            match value {
+                None => store.insert_large(key, stdin).unwrap(),
+                Some(len) => {
+                    let len = len.parse().expect(USAGE);
+                    store.insert_from_reader(key, stdin, len).unwrap();
+                }
+            }
+        },
+        _ => eprintln!("{}", USAGE),
+    }
+}
+
+/// Times are either seconds since the Unix epoch or RFC 3339 timestamps
+/// such as 2024-05-01T12:00:00Z.
+fn parse_restore_point(flag: Option<&String>, value: Option<&String>) -> Option<RestorePoint> {
+    let value = value?;
+    match flag?.as_str() {
+        "--until-offset" => value.parse().ok().map(RestorePoint::Offset),
+        "--until-time" => {
+            let time = match value.parse::<u64>() {
+                Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+                Err(_) => humantime::parse_rfc3339_weak(value).ok()?,
+            };
+            Some(RestorePoint::Time(time))
         },
-        _ => eprintln!("{}", &USAGE),
+        _ => None,
     }
-}
\ No newline at end of file
+}
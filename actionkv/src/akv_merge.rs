@@ -0,0 +1,56 @@
+use libactionkv::diff::{merge, ConflictPolicy};
+use libactionkv::ActionKV;
+
+#[cfg(target_os="windows")]
+const USAGE: &str = r#"
+Usage:
+    akv_merge.exe A B OUT [--prefer a|b|last-writer]
+
+Writes a new store OUT with the live keys and column families of both A
+and B. Keys with different values in A and B take the value from the store
+given by --prefer, or by default from whichever store wrote the key last.
+
+last-writer goes by the time each record was written, as read from the
+writing machine's clock. If the stores were written on machines whose
+clocks disagree, the older value can win.
+"#;
+
+#[cfg(not(target_os="windows"))]
+const USAGE: &str = r#"
+Usage:
+    akv_merge A B OUT [--prefer a|b|last-writer]
+
+Writes a new store OUT with the live keys and column families of both A
+and B. Keys with different values in A and B take the value from the store
+given by --prefer, or by default from whichever store wrote the key last.
+
+last-writer goes by the time each record was written, as read from the
+writing machine's clock. If the stores were written on machines whose
+clocks disagree, the older value can win.
+"#;
+
+fn open(file_name: &str) -> ActionKV {
+    let mut store = ActionKV::open_read_only(std::path::Path::new(file_name)).expect("Unable to open file");
+    store.load().expect("Unable to load data from store");
+    store
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut a = open(args.get(1).expect(USAGE));
+    let mut b = open(args.get(2).expect(USAGE));
+    let out = std::path::Path::new(args.get(3).expect(USAGE));
+    let policy = match args.get(4).map(String::as_str) {
+        None => ConflictPolicy::LastWriter,
+        Some("--prefer") => match args.get(5).expect(USAGE).as_str() {
+            "a" => ConflictPolicy::PreferA,
+            "b" => ConflictPolicy::PreferB,
+            "last-writer" => ConflictPolicy::LastWriter,
+            _ => panic!("{}", USAGE),
+        },
+        Some(_) => panic!("{}", USAGE),
+    };
+
+    let conflicts = merge(&mut a, &mut b, out, policy).expect("Unable to merge stores");
+    println!("Merged into {} ({} conflicting keys)", out.display(), conflicts);
+}
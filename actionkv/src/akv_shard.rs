@@ -0,0 +1,70 @@
+use libactionkv::ShardedStore;
+
+#[cfg(target_os="windows")]
+const USAGE: &str = r#"
+Usage:
+    akv_shard.exe DIR [--shards N] get KEY
+    akv_shard.exe DIR [--shards N] delete KEY
+    akv_shard.exe DIR [--shards N] insert KEY VALUE
+    akv_shard.exe DIR [--shards N] update KEY VALUE
+    akv_shard.exe DIR reshard N
+    akv_shard.exe DIR shards
+
+--shards sets the number of shards for a new store [default: 4].
+"#;
+
+#[cfg(not(target_os="windows"))]
+const USAGE: &str = r#"
+Usage:
+    akv_shard DIR [--shards N] get KEY
+    akv_shard DIR [--shards N] delete KEY
+    akv_shard DIR [--shards N] insert KEY VALUE
+    akv_shard DIR [--shards N] update KEY VALUE
+    akv_shard DIR reshard N
+    akv_shard DIR shards
+
+--shards sets the number of shards for a new store [default: 4].
+"#;
+
+const DEFAULT_SHARDS: u32 = 4;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let shards = match args.iter().position(|arg| arg == "--shards") {
+        Some(at) => {
+            let shards = args.get(at + 1).and_then(|n| n.parse().ok()).expect(USAGE);
+            args.drain(at..at + 2);
+            shards
+        },
+        None => DEFAULT_SHARDS,
+    };
+    let dir_name = args.get(1).expect(USAGE);
+    let action = args.get(2).expect(USAGE).as_ref();
+
+    let dir = std::path::Path::new(&dir_name);
+    let store = ShardedStore::open(dir, shards).expect("Unable to open store");
+
+    match action {
+        "shards" => println!("{}", store.shards()),
+        "reshard" => {
+            let shards = args.get(3).and_then(|n| n.parse().ok()).expect(USAGE);
+            store.reshard(shards).expect("Unable to reshard");
+        },
+        _ => {
+            let key = args.get(3).expect(USAGE).as_bytes();
+            let value = args.get(4);
+            match action {
+                "get" => match store.get(key).expect("Failed to get") {
+                    None => eprintln!("{} not found", String::from_utf8_lossy(key)),
+                    Some(value) => println!("{}", String::from_utf8_lossy(value.as_slice()))
+                },
+                "delete" => store.delete(key).unwrap(),
+                "insert" | "update" => {
+                    let value = value.expect(USAGE).as_bytes();
+                    store.insert(key, value).unwrap();
+                },
+                _ => eprintln!("{}", USAGE),
+            }
+        },
+    }
+}
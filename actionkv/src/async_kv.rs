@@ -0,0 +1,264 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use crate::large::{chunk_next, Location, Manifest, CHUNK_LINK_LEN};
+use crate::{ActionKV, ByteStr, ByteString, KeyValuePair};
+
+// Writes queued for the writer task before callers have to wait
+const WRITE_QUEUE_LEN: usize = 64;
+
+enum Write {
+    Insert(ByteString, ByteString, oneshot::Sender<io::Result<()>>),
+    Merge(ByteString, String, ByteString, oneshot::Sender<io::Result<()>>),
+    Compact(oneshot::Sender<io::Result<()>>),
+}
+
+/// An `ActionKV` for async code. Cloning it is cheap and every clone refers
+/// to the same store.
+///
+/// Writes are applied one at a time, in the order they were made, by a
+/// writer task that owns the write side of the store. Reads don't go
+/// through the writer and run concurrently with each other on tokio's
+/// blocking threads. Both wait for a write in progress to finish, so a read
+/// never sees half of a write.
+#[derive(Clone)]
+pub struct AsyncActionKV {
+    store: Arc<RwLock<ActionKV>>,
+    writes: mpsc::Sender<Write>,
+}
+
+impl AsyncActionKV {
+    /// Opens and loads the store at `path` like `ActionKV::open`, and starts
+    /// its writer task. The store stays open until every clone is dropped.
+    pub async fn open(path: impl Into<PathBuf>) -> io::Result<AsyncActionKV> {
+        let path = path.into();
+        let store = task::spawn_blocking(move || {
+            let mut store = ActionKV::open(&path)?;
+            store.load()?;
+            Ok::<_, io::Error>(store)
+        }).await.map_err(io::Error::other)??;
+
+        let store = Arc::new(RwLock::new(store));
+        let (writes, queue) = mpsc::channel(WRITE_QUEUE_LEN);
+        let writer = Arc::clone(&store);
+        task::spawn_blocking(move || run_writer(writer, queue));
+
+        Ok(AsyncActionKV { store, writes })
+    }
+
+    pub async fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let key = key.to_vec();
+        self.read(move |store| {
+            let started = Instant::now();
+            let value = get_shared(store, &key);
+            store.metrics.gets.fetch_add(1, Ordering::Relaxed);
+            store.metrics.get_latency.observe(started.elapsed());
+            value
+        }).await
+    }
+
+    /// Like `ActionKV::scan`.
+    pub async fn scan(&self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let prefix = prefix.to_vec();
+        self.read(move |store| {
+            let mut pairs = Vec::new();
+            for key in store.keys_with_prefix(&prefix) {
+                let value = get_shared(store, &key)?.unwrap_or_default();
+                if !value.is_empty() {
+                    pairs.push(KeyValuePair { key, value });
+                }
+            }
+            Ok(pairs)
+        }).await
+    }
+
+    pub async fn insert(&self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.write(|done| Write::Insert(key, value, done)).await
+    }
+
+    pub async fn delete(&self, key: &ByteStr) -> io::Result<()> {
+        self.insert(key, b"").await
+    }
+
+    pub async fn update(&self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.insert(key, value).await
+    }
+
+    /// Like `ActionKV::merge`. Merges from any number of tasks are all
+    /// applied, unlike a `get` followed by an `insert`.
+    pub async fn merge(&self, key: &ByteStr, operator: &str, operand: &ByteStr) -> io::Result<()> {
+        let (key, operator, operand) = (key.to_vec(), operator.to_string(), operand.to_vec());
+        self.write(|done| Write::Merge(key, operator, operand, done)).await
+    }
+
+    pub async fn compact(&self) -> io::Result<()> {
+        self.write(Write::Compact).await
+    }
+
+    async fn read<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ActionKV) -> io::Result<T> + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        task::spawn_blocking(move || {
+            let store = store.read().unwrap_or_else(PoisonError::into_inner);
+            f(&store)
+        }).await.map_err(io::Error::other)?
+    }
+
+    async fn write(&self, write: impl FnOnce(oneshot::Sender<io::Result<()>>) -> Write) -> io::Result<()> {
+        let (done, result) = oneshot::channel();
+        self.writes.send(write(done)).await.map_err(|_| writer_gone())?;
+        result.await.map_err(|_| writer_gone())?
+    }
+}
+
+fn run_writer(store: Arc<RwLock<ActionKV>>, mut queue: mpsc::Receiver<Write>) {
+    while let Some(write) = queue.blocking_recv() {
+        let mut store = store.write().unwrap_or_else(PoisonError::into_inner);
+        // The caller may have given up waiting, which doesn't undo the write
+        let _ = match write {
+            Write::Insert(key, value, done) => done.send(store.insert(&key, &value)),
+            Write::Merge(key, operator, operand, done) => done.send(store.merge(&key, &operator, &operand)),
+            Write::Compact(done) => done.send(store.compact()),
+        };
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::other("the store's writer task has stopped")
+}
+
+/// `ActionKV::get` for a shared store. Records are read with positioned
+/// reads, which leave the file's cursor alone, so any number of these can
+/// run at once.
+fn get_shared(store: &ActionKV, key: &ByteStr) -> io::Result<Option<ByteString>> {
+    let base = get_base_shared(store, key)?;
+    let mut operands = Vec::new();
+    for &position in store.merges.get(key).into_iter().flatten() {
+        operands.push(read_at(store, position)?.value);
+    }
+    store.apply_merges(key, base, &operands)
+}
+
+fn get_base_shared(store: &ActionKV, key: &ByteStr) -> io::Result<Option<ByteString>> {
+    match store.locate(key) {
+        None => Ok(None),
+        Some(Location::Record(position)) => Ok(Some(read_at(store, position)?.value)),
+        Some(Location::Large(position)) => {
+            let manifest = Manifest::decode(&read_at(store, position)?.value)?;
+            let mut value = ByteString::with_capacity(manifest.total_len as usize);
+            let mut next = Some(manifest.first_chunk);
+            while let Some(position) = next {
+                let chunk = read_at(store, position)?.value;
+                next = chunk_next(&chunk)?;
+                value.extend_from_slice(&chunk[CHUNK_LINK_LEN..]);
+            }
+            Ok(Some(value))
+        },
+    }
+}
+
+fn read_at(store: &ActionKV, position: u64) -> io::Result<KeyValuePair> {
+    let end = store.file.metadata()?.len();
+    let mut record = BufReader::new(PositionedReader { file: &store.file, position });
+    ActionKV::process_record(&mut record, store.checksum, position, end)
+}
+
+// Reads share the file with the writer task, so they have to leave its
+// cursor alone, which needs the platform's positioned reads
+#[cfg(not(any(unix, windows)))]
+compile_error!("the async feature needs positioned file reads, which are only available on unix and windows");
+
+struct PositionedReader<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(self.file, buf, self.position)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn matches_the_sync_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("async.akv");
+        let store = AsyncActionKV::open(&path).await.unwrap();
+
+        let writes: Vec<_> = (0..20u8).map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move { store.insert(&[b'k', i], &[i]).await })
+        }).collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        store.delete(&[b'k', 3]).await.unwrap();
+
+        let reads: Vec<_> = (0..20u8).map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move { store.get(&[b'k', i]).await })
+        }).collect();
+        for (i, read) in reads.into_iter().enumerate() {
+            let expected = if i == 3 { Some(vec![]) } else { Some(vec![i as u8]) };
+            assert_eq!(read.await.unwrap().unwrap(), expected);
+        }
+        assert_eq!(store.scan(b"k").await.unwrap().len(), 19);
+
+        let merges: Vec<_> = (0..10u64).map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move { store.merge(b"count", "u64_add", &i.to_le_bytes()).await })
+        }).collect();
+        for merge in merges {
+            merge.await.unwrap().unwrap();
+        }
+        assert_eq!(store.get(b"count").await.unwrap(), Some(45u64.to_le_bytes().to_vec()));
+
+        let err = store.insert(b"\xffakv\x00large\x00k", b"v").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        drop(store);
+
+        // Let the writer task notice the store is gone and release the lock
+        let mut sync = loop {
+            match ActionKV::open(&path) {
+                Ok(store) => break store,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => tokio::task::yield_now().await,
+                Err(err) => panic!("{}", err),
+            }
+        };
+        sync.load().unwrap();
+        assert_eq!(sync.get(&[b'k', 7]).unwrap(), Some(vec![7]));
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chunked.akv");
+        let mut sync = ActionKV::open(&path).unwrap();
+        sync.load().unwrap();
+        let value: ByteString = (0..50u8).collect();
+        sync.insert_chunked(b"big", &value[..], 16).unwrap();
+        drop(sync);
+
+        let store = AsyncActionKV::open(&path).await.unwrap();
+        assert_eq!(store.get(b"big").await.unwrap(), Some(value));
+    }
+}
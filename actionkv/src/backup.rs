@@ -0,0 +1,225 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::checksum::RecordHeader;
+use crate::{ActionKV, ByteString, RECORD_HEADER_LEN};
+
+/// How far into a store's history `restore` goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Keep the records that end at or before this file offset
+    Offset(u64),
+    /// Keep the records written at or before this time
+    Time(SystemTime),
+}
+
+impl ActionKV {
+    /// Copies the store to `dest` as it is now and returns the number of
+    /// bytes copied. Writes can't happen during the copy, since they need
+    /// the same `&mut self`, so the backup is always consistent.
+    ///
+    /// The copy is written next to `dest` and renamed into place once it is
+    /// on disk, so a failed backup never leaves a partial file at `dest`.
+    pub fn backup(&mut self, dest: &Path) -> io::Result<u64> {
+        let len = self.file.metadata()?.len();
+        self.copy_prefix(dest, len)
+    }
+
+    /// Copies the first `len` bytes of the file to `dest`, through a
+    /// temporary file next to it.
+    fn copy_prefix(&mut self, dest: &Path, len: u64) -> io::Result<u64> {
+        let tmp_path = tmp_path(dest);
+        let result = (|| {
+            let mut tmp = File::create(&tmp_path)?;
+            self.file.seek(SeekFrom::Start(0))?;
+            let copied = io::copy(&mut Read::by_ref(&mut self.file).take(len), &mut tmp)?;
+            let started = Instant::now();
+            tmp.sync_all()?;
+            self.metrics.fsync_latency.observe(started.elapsed());
+            Ok(copied)
+        })();
+
+        match result {
+            Ok(copied) => {
+                fs::rename(&tmp_path, dest)?;
+                Ok(copied)
+            },
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(err)
+            },
+        }
+    }
+}
+
+/// Copies the store at `src` to `dest` without locking it, so that it works
+/// while another process has the store open for writing, and returns the
+/// number of bytes copied.
+///
+/// The store is a log that is only ever appended to, so the copy ends at
+/// the last whole record and holds the store as it was after some write.
+/// A write still in progress is left out. Like `backup`, the copy is
+/// renamed into place once it is on disk.
+///
+/// Windows locks keep other processes from reading the file as well, so
+/// there this fails while a writer has the store open.
+pub fn backup_live(src: &Path, dest: &Path) -> io::Result<u64> {
+    let mut store = ActionKV::open_unlocked(src)?;
+    let len = restore_len(&mut store, RestorePoint::Offset(u64::MAX))?;
+    store.copy_prefix(dest, len)
+}
+
+/// Writes a new store at `dest` holding the history of the store at `src` up
+/// to `until`, and returns the length of the restored file.
+///
+/// The store is a log, so this keeps the prefix of records written before
+/// the restore point and drops the rest. Compaction rewrites the log, so
+/// a store can only be restored to points after its last compaction.
+/// Records migrated from before format version 3 have no timestamp and count
+/// as older than any time.
+///
+/// `dest` must not exist yet.
+pub fn restore(src: &Path, dest: &Path, until: RestorePoint) -> io::Result<u64> {
+    let mut store = ActionKV::open_read_only(src)?;
+    let cut = restore_len(&mut store, until)?;
+
+    let mut out = OpenOptions::new().write(true).create_new(true).open(dest)?;
+    store.file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut Read::by_ref(&mut store.file).take(cut), &mut out)?;
+    out.sync_all()?;
+
+    Ok(cut)
+}
+
+/// Finds the length of the prefix of the file that ends at `until`.
+fn restore_len(store: &mut ActionKV, until: RestorePoint) -> io::Result<u64> {
+    let until = match until {
+        RestorePoint::Offset(offset) => Until::Offset(offset),
+        RestorePoint::Time(time) => {
+            let millis = time.duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or(0);
+            Until::Millis(millis)
+        },
+    };
+
+    let end = store.file.metadata()?.len();
+    let mut f = BufReader::new(&mut store.file);
+    let mut position = f.seek(SeekFrom::Start(store.data_start))?;
+    while position + RECORD_HEADER_LEN <= end {
+        let header = RecordHeader::read(&mut f, position)?;
+        let data_len = header.key_len as u64 + header.value_len as u64;
+        let record_end = position + RECORD_HEADER_LEN + data_len;
+        // A torn write at the tail is never part of a restore
+        if record_end > end {
+            break;
+        }
+        let keep = match until {
+            Until::Offset(offset) => record_end <= offset,
+            Until::Millis(millis) => header.timestamp <= millis,
+        };
+        if !keep {
+            break;
+        }
+
+        let mut data = ByteString::with_capacity(data_len as usize);
+        Read::by_ref(&mut f).take(data_len).read_to_end(&mut data)?;
+        let value = data.split_off(header.key_len as usize);
+        let computed = store.checksum.record(&data, &value);
+        if computed != header.checksum {
+            return Err(crate::checksum::corruption(position, computed, header.checksum));
+        }
+        position = record_end;
+    }
+
+    Ok(position)
+}
+
+enum Until {
+    Offset(u64),
+    Millis(u64),
+}
+
+fn tmp_path(dest: &Path) -> PathBuf {
+    let mut tmp = dest.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn backup_copies_the_whole_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("live.akv")).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert_chunked(b"big", &[9u8; 40][..], 16).unwrap();
+
+        let dest = dir.path().join("backup.akv");
+        store.backup(&dest).unwrap();
+        store.insert(b"a", b"2").unwrap();
+
+        let mut backup = ActionKV::open_read_only(&dest).unwrap();
+        backup.load().unwrap();
+        assert_eq!(backup.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(backup.get(b"big").unwrap(), Some(vec![9u8; 40]));
+    }
+
+    #[test]
+    fn live_backup_skips_the_write_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("live.akv");
+        let mut store = ActionKV::open(&src).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let whole_len = fs::metadata(&src).unwrap().len();
+        // Half of a record, as a writer in the middle of an append leaves it
+        store.insert(b"a", b"2").unwrap();
+        OpenOptions::new().write(true).open(&src).unwrap().set_len(whole_len + 10).unwrap();
+
+        // The writer still holds its lock
+        let dest = dir.path().join("backup.akv");
+        assert_eq!(backup_live(&src, &dest).unwrap(), whole_len);
+        drop(store);
+
+        let mut backup = ActionKV::open_read_only(&dest).unwrap();
+        backup.load().unwrap();
+        assert_eq!(backup.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn restore_stops_at_offset_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("history.akv");
+        let mut store = ActionKV::open(&src).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let first_end = std::fs::metadata(&src).unwrap().len();
+        store.insert(b"a", b"2").unwrap();
+        drop(store);
+
+        // An offset in the middle of the second record keeps only the first
+        let dest = dir.path().join("by-offset.akv");
+        assert_eq!(restore(&src, &dest, RestorePoint::Offset(first_end + 3)).unwrap(), first_end);
+        let mut restored = ActionKV::open(&dest).unwrap();
+        restored.load().unwrap();
+        assert_eq!(restored.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        let dest = dir.path().join("by-time.akv");
+        let long_ago = UNIX_EPOCH + Duration::from_secs(1);
+        restore(&src, &dest, RestorePoint::Time(long_ago)).unwrap();
+        let mut restored = ActionKV::open(&dest).unwrap();
+        restored.load().unwrap();
+        assert_eq!(restored.get(b"a").unwrap(), None);
+
+        let err = restore(&src, &dest, RestorePoint::Time(SystemTime::now())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+}
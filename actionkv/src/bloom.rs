@@ -0,0 +1,150 @@
+use serde_derive::{Deserialize, Serialize};
+use xxhash_rust::xxh64::xxh64;
+use crate::{parse_internal_key, ActionKV, ByteStr};
+
+/// A set of keys that can answer "definitely not present" without touching
+/// the disk. Keys can't be removed, so deleted keys still read as possibly
+/// present.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    expected_keys: usize,
+    false_positive_rate: f64,
+}
+
+impl BloomFilter {
+    /// Sizes a filter to give `false_positive_rate` once it holds
+    /// `expected_keys` keys. More keys than that make false positives more
+    /// likely but are otherwise fine.
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> BloomFilter {
+        let n = expected_keys.max(1) as f64;
+        let p = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            expected_keys,
+            false_positive_rate,
+        }
+    }
+
+    /// An empty filter sized like this one.
+    pub fn empty_like(&self) -> BloomFilter {
+        BloomFilter::new(self.expected_keys, self.false_positive_rate)
+    }
+
+    pub fn insert(&mut self, key: &ByteStr) {
+        for bit in self.bit_positions(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False if `key` was never inserted. True if it was, or by chance.
+    pub fn may_contain(&self, key: &ByteStr) -> bool {
+        self.bit_positions(key).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // Double hashing: the i-th position is h1 + i * h2
+    fn bit_positions(&self, key: &ByteStr) -> impl Iterator<Item = u64> {
+        let h1 = xxh64(key, 0);
+        let h2 = xxh64(key, h1) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+impl ActionKV {
+    /// Starts keeping a bloom filter of the store's keys, which lets `get`
+    /// and `find` turn away keys that were never written without reading
+    /// the file. Keys already loaded are added to it, and `load` and every
+    /// insert keep it up to date.
+    pub fn enable_bloom_filter(&mut self, expected_keys: usize, false_positive_rate: f64) {
+        self.set_bloom_filter(BloomFilter::new(expected_keys, false_positive_rate));
+    }
+
+    /// Uses `filter`, say one saved by an earlier process, adding the keys
+    /// already loaded to it.
+    pub fn set_bloom_filter(&mut self, mut filter: BloomFilter) {
+        for key in self.index.keys() {
+            filter.insert(bloom_key(key));
+        }
+        self.bloom = Some(filter);
+    }
+
+    pub fn bloom_filter(&self) -> Option<&BloomFilter> {
+        self.bloom.as_ref()
+    }
+
+    /// False if the bloom filter knows `key` was never written.
+    pub(crate) fn may_contain(&self, key: &ByteStr) -> bool {
+        self.bloom.as_ref().is_none_or(|bloom| bloom.may_contain(key))
+    }
+}
+
+/// The key a record adds to the bloom filter. Large values are found
+/// through their manifest, which is filed under the user's key.
+pub(crate) fn bloom_key(key: &ByteStr) -> &ByteStr {
+    match parse_internal_key(key) {
+        Some((_, user_key)) => user_key,
+        None => key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives_and_few_false_positives() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(format!("key{}", i).as_bytes());
+        }
+        assert!((0..1000).all(|i| bloom.may_contain(format!("key{}", i).as_bytes())));
+
+        let false_positives = (0..10_000)
+            .filter(|i| bloom.may_contain(format!("other{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn store_keeps_its_filter_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bloom.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"before", b"1").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.enable_bloom_filter(100, 0.01);
+        store.load().unwrap();
+        store.insert(b"after", b"2").unwrap();
+        store.insert_chunked(b"large", &[3u8; 40][..], 16).unwrap();
+
+        let bloom = store.bloom_filter().unwrap();
+        assert!(bloom.may_contain(b"before") && bloom.may_contain(b"after") && bloom.may_contain(b"large"));
+        assert_eq!(store.get(b"large").unwrap(), Some(vec![3u8; 40]));
+        assert_eq!(store.find(b"before").unwrap().map(|(_, value)| value), Some(b"1".to_vec()));
+        assert_eq!(store.find(b"never").unwrap(), None);
+
+        store.compact().unwrap();
+        assert!(store.bloom_filter().unwrap().may_contain(b"after"));
+    }
+
+    #[test]
+    fn find_sees_records_written_around_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("bloom.akv")).unwrap();
+        store.enable_bloom_filter(100, 0.01);
+        store.load().unwrap();
+        store.insert_but_ignore_index(b"unindexed", b"1").unwrap();
+        assert_eq!(store.find(b"unindexed").unwrap().map(|(_, value)| value), Some(b"1".to_vec()));
+    }
+}
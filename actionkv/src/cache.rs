@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::{ByteStr, ByteString};
+
+/// How a store's value cache has done since the store was opened.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes of keys and values held, out of `capacity`
+    pub bytes: usize,
+    pub capacity: usize,
+    pub entries: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: ByteString,
+    // When the entry was last used, which is also its key in `by_use`
+    used: u64,
+}
+
+/// A least-recently-used cache of values, bounded by the bytes of the keys
+/// and values it holds.
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    entries: HashMap<ByteString, Entry>,
+    by_use: BTreeMap<u64, ByteString>,
+    clock: u64,
+    bytes: usize,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Looks up `key`, counting a hit or a miss.
+    pub(crate) fn get(&mut self, key: &ByteStr) -> Option<ByteString> {
+        self.clock += 1;
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => {
+                self.misses += 1;
+                return None;
+            },
+        };
+        self.hits += 1;
+        let key = self.by_use.remove(&entry.used).unwrap();
+        entry.used = self.clock;
+        self.by_use.insert(self.clock, key);
+        Some(entry.value.clone())
+    }
+
+    /// Caches `value`, evicting the least recently used values to make
+    /// room. Values bigger than the whole cache aren't kept.
+    pub(crate) fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.remove(key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        while self.bytes + size > self.capacity {
+            let (_, oldest) = self.by_use.pop_first().unwrap();
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.bytes -= oldest.len() + entry.value.len();
+        }
+
+        self.clock += 1;
+        self.by_use.insert(self.clock, key.to_vec());
+        self.entries.insert(key.to_vec(), Entry { value: value.to_vec(), used: self.clock });
+        self.bytes += size;
+    }
+
+    pub(crate) fn remove(&mut self, key: &ByteStr) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_use.remove(&entry.used);
+            self.bytes -= key.len() + entry.value.len();
+        }
+    }
+
+    /// Drops every value but keeps the counters.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.by_use.clear();
+        self.bytes = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            bytes: self.bytes,
+            capacity: self.capacity,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActionKV, StoreOptions};
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ValueCache::new(12);
+        cache.insert(b"a", b"11111");
+        cache.insert(b"b", b"22222");
+        assert_eq!(cache.get(b"a"), Some(b"11111".to_vec()));
+        cache.insert(b"c", b"33333");
+
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.get(b"a"), Some(b"11111".to_vec()));
+        assert_eq!(cache.get(b"c"), Some(b"33333".to_vec()));
+        cache.insert(b"big", &[0; 20]);
+        assert_eq!(cache.get(b"big"), None);
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 2, bytes: 12, capacity: 12, entries: 2 });
+    }
+
+    #[test]
+    fn store_invalidates_on_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions { cache_bytes: Some(1024), ..StoreOptions::default() };
+        let mut store = ActionKV::open_with(&dir.path().join("cache.akv"), &options).unwrap();
+        store.load().unwrap();
+
+        store.insert(b"k", b"v1").unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v1".to_vec()));
+        store.insert(b"k", b"v2").unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v2".to_vec()));
+        store.merge(b"k", "append", b"!").unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v2!".to_vec()));
+        store.insert_chunked(b"k", &b"large"[..], 2).unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"large".to_vec()));
+        store.delete(b"k").unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(vec![]));
+
+        store.compact().unwrap();
+        assert_eq!(store.get(b"k").unwrap(), None);
+        let stats = store.stats().unwrap().cache.unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 6));
+    }
+}
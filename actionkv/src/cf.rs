@@ -0,0 +1,267 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::time::Instant;
+use crate::{internal_key, parse_internal_key, ActionKV, ByteStr, ByteString, KeyValuePair};
+
+// Records holding a key of a column family, keyed by name, a 0 byte and key
+pub(crate) const CF_KIND: &ByteStr = b"cf";
+// Records that create (non-empty value) or drop (empty value) a column family
+pub(crate) const CF_DEF_KIND: &ByteStr = b"cfdef";
+
+const CREATED: &ByteStr = b"+";
+
+/// The index of one column family, and where it was created. Records of an
+/// earlier column family with the same name come before that and are
+/// ignored.
+#[derive(Debug, Default)]
+pub(crate) struct CfIndex {
+    pub created_at: u64,
+    pub index: HashMap<ByteString, u64>,
+}
+
+fn cf_key(name: &str, key: &ByteStr) -> ByteString {
+    let mut payload = ByteString::with_capacity(name.len() + 1 + key.len());
+    payload.extend_from_slice(name.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(key);
+    internal_key(CF_KIND, &payload)
+}
+
+/// Splits the key of a column family record into the family's name and the
+/// key within it.
+pub(crate) fn parse_cf_key(key: &ByteStr) -> Option<(&str, &ByteStr)> {
+    match parse_internal_key(key) {
+        Some((CF_KIND, payload)) => {
+            let split = payload.iter().position(|&b| b == 0)?;
+            let name = std::str::from_utf8(&payload[..split]).ok()?;
+            Some((name, &payload[split + 1..]))
+        },
+        _ => None,
+    }
+}
+
+/// Applies a record read by `load` to the column family indexes. Returns
+/// false for records that don't belong to a column family.
+pub(crate) fn index_record(
+    cfs: &mut HashMap<String, CfIndex>,
+    kv: &KeyValuePair,
+    position: u64,
+) -> bool {
+    if let Some((name, key)) = parse_cf_key(&kv.key) {
+        if let Some(cf) = cfs.get_mut(name) {
+            cf.index.insert(key.to_vec(), position);
+        }
+        return true;
+    }
+
+    match parse_internal_key(&kv.key) {
+        Some((CF_DEF_KIND, name)) => {
+            let name = String::from_utf8_lossy(name).into_owned();
+            if kv.value.is_empty() {
+                cfs.remove(&name);
+            } else {
+                cfs.insert(name, CfIndex { created_at: position, index: HashMap::new() });
+            }
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Whether a record is still needed, for column family records. Returns
+/// `None` for other records.
+pub(crate) fn is_live_record(
+    cfs: &HashMap<String, CfIndex>,
+    kv: &KeyValuePair,
+    position: u64,
+) -> Option<bool> {
+    if let Some((name, key)) = parse_cf_key(&kv.key) {
+        let current = cfs.get(name).and_then(|cf| cf.index.get(key));
+        return Some(current == Some(&position) && !kv.value.is_empty());
+    }
+    match parse_internal_key(&kv.key) {
+        Some((CF_DEF_KIND, name)) => {
+            let name = String::from_utf8_lossy(name);
+            Some(cfs.get(name.as_ref()).map(|cf| cf.created_at) == Some(position))
+        },
+        _ => None,
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("column family {:?} doesn't exist", name)
+    )
+}
+
+/// A named set of keys with its own index, sharing the store's log. Keys in
+/// different column families, or in a column family and the store itself,
+/// never clash.
+pub struct ColumnFamily<'a> {
+    store: &'a mut ActionKV,
+    name: String,
+}
+
+impl ColumnFamily<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let position = match self.store.column_families[&self.name].index.get(key) {
+            None => return Ok(None),
+            Some(&position) => position,
+        };
+        Ok(Some(self.store.get_at(position)?.value))
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let started = Instant::now();
+        let position = self.store.insert_but_ignore_index(&cf_key(&self.name, key), value)?;
+        self.store.column_families.get_mut(&self.name).unwrap().index.insert(key.to_vec(), position);
+        self.store.metrics.record_write(value.len() as u64, started);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.insert(key, value)
+    }
+
+    #[inline]
+    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
+        self.insert(key, b"")
+    }
+
+    /// Like `ActionKV::scan`, within the column family.
+    pub fn scan(&mut self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let keys: BTreeSet<ByteString> = self.store.column_families[&self.name].index.keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+
+        let mut pairs = Vec::new();
+        for key in keys {
+            let value = self.get(&key)?.unwrap_or_default();
+            if !value.is_empty() {
+                pairs.push(KeyValuePair { key, value });
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+impl ActionKV {
+    /// Creates an empty column family.
+    pub fn create_cf(&mut self, name: &str) -> io::Result<()> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "column family names must be non-empty and can't contain \\0"
+            ));
+        }
+        if self.column_families.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("column family {:?} already exists", name)
+            ));
+        }
+
+        let position = self.insert_but_ignore_index(&internal_key(CF_DEF_KIND, name.as_bytes()), CREATED)?;
+        self.column_families.insert(name.to_string(), CfIndex { created_at: position, index: HashMap::new() });
+        Ok(())
+    }
+
+    /// Drops a column family and every key in it. Its records stay in the
+    /// file until the next compaction.
+    pub fn drop_cf(&mut self, name: &str) -> io::Result<()> {
+        if !self.column_families.contains_key(name) {
+            return Err(not_found(name));
+        }
+        self.insert_but_ignore_index(&internal_key(CF_DEF_KIND, name.as_bytes()), b"")?;
+        self.column_families.remove(name);
+        Ok(())
+    }
+
+    /// The names of the column families, sorted.
+    pub fn column_families(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.column_families.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn cf(&mut self, name: &str) -> io::Result<ColumnFamily<'_>> {
+        if !self.column_families.contains_key(name) {
+            return Err(not_found(name));
+        }
+        Ok(ColumnFamily { store: self, name: name.to_string() })
+    }
+
+    /// Copies every column family's live keys into `compacted`.
+    pub(crate) fn compact_cfs(&mut self, compacted: &mut ActionKV) -> io::Result<()> {
+        let names: Vec<String> = self.column_families().into_iter().map(String::from).collect();
+        for name in names {
+            compacted.create_cf(&name)?;
+            for kv in self.cf(&name)?.scan(b"")? {
+                compacted.cf(&name)?.insert(&kv.key, &kv.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_families_are_separate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cf.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.create_cf("users").unwrap();
+        store.create_cf("orders").unwrap();
+        assert_eq!(store.create_cf("users").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        store.insert(b"k", b"default").unwrap();
+        store.cf("users").unwrap().insert(b"k", b"user").unwrap();
+        store.cf("orders").unwrap().insert(b"k", b"order").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.column_families(), vec!["orders", "users"]);
+        assert_eq!(store.get(b"k").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(store.cf("users").unwrap().get(b"k").unwrap(), Some(b"user".to_vec()));
+        assert_eq!(store.cf("orders").unwrap().get(b"k").unwrap(), Some(b"order".to_vec()));
+        assert_eq!(store.scan(b"").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dropped_column_families_are_compacted_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drop.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.create_cf("tmp").unwrap();
+        store.cf("tmp").unwrap().insert(b"a", b"1").unwrap();
+        store.create_cf("kept").unwrap();
+        store.cf("kept").unwrap().insert(b"b", b"2").unwrap();
+        store.drop_cf("tmp").unwrap();
+        assert_eq!(store.cf("tmp").err().map(|err| err.kind()), Some(io::ErrorKind::NotFound));
+
+        // A new column family with the old name starts out empty
+        store.create_cf("tmp").unwrap();
+        assert_eq!(store.cf("tmp").unwrap().get(b"a").unwrap(), None);
+        store.drop_cf("tmp").unwrap();
+
+        let stale = store.stats().unwrap().stale_bytes;
+        assert!(stale > 0);
+        store.compact().unwrap();
+        assert_eq!(store.stats().unwrap().stale_bytes, 0);
+        assert_eq!(store.column_families(), vec!["kept"]);
+        assert_eq!(store.cf("kept").unwrap().get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use xxhash_rust::xxh64::Xxh64;
+
+/// The checksum algorithm a store uses for its records. It is chosen when
+/// the store is created and recorded in the file header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC-32 (IEEE), the only checksum before format version 2
+    #[default]
+    Crc32,
+    /// CRC-32C (Castagnoli), with better error detection than CRC-32
+    Crc32c,
+    /// 64 bit xxHash, much less likely to miss corruption in big records
+    XxHash64,
+}
+
+impl Checksum {
+    pub(crate) fn code(self) -> u32 {
+        match self {
+            Checksum::Crc32 => 0,
+            Checksum::Crc32c => 1,
+            Checksum::XxHash64 => 2,
+        }
+    }
+
+    pub(crate) fn from_code(code: u32) -> io::Result<Checksum> {
+        match code {
+            0 => Ok(Checksum::Crc32),
+            1 => Ok(Checksum::Crc32c),
+            2 => Ok(Checksum::XxHash64),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown checksum algorithm {}", code)
+            )),
+        }
+    }
+
+    pub(crate) fn hasher(self) -> RecordHasher {
+        match self {
+            Checksum::Crc32 => RecordHasher::Crc32(&crc32::IEEE_TABLE, 0),
+            Checksum::Crc32c => RecordHasher::Crc32(&crc32::CASTAGNOLI_TABLE, 0),
+            Checksum::XxHash64 => RecordHasher::XxHash64(Xxh64::new(0)),
+        }
+    }
+
+    /// Checksums a whole record. The lengths are covered along with the data
+    /// so that a corrupted length can't go unnoticed.
+    pub(crate) fn record(self, key: &[u8], value: &[u8]) -> u64 {
+        let mut hasher = self.hasher();
+        hasher.lengths(key.len() as u32, value.len() as u32);
+        hasher.write(key);
+        hasher.write(value);
+        hasher.finish()
+    }
+}
+
+/// Computes a record checksum incrementally.
+pub(crate) enum RecordHasher {
+    // The lookup table for the polynomial and the CRC so far
+    Crc32(&'static [u32; 256], u32),
+    XxHash64(Xxh64),
+}
+
+impl RecordHasher {
+    pub(crate) fn write(&mut self, data: &[u8]) {
+        match self {
+            RecordHasher::Crc32(table, crc) => *crc = crc32::update(*crc, table, data),
+            RecordHasher::XxHash64(hasher) => hasher.update(data),
+        }
+    }
+
+    pub(crate) fn lengths(&mut self, key_len: u32, value_len: u32) {
+        self.write(&key_len.to_le_bytes());
+        self.write(&value_len.to_le_bytes());
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        match self {
+            RecordHasher::Crc32(_, crc) => *crc as u64,
+            RecordHasher::XxHash64(hasher) => hasher.digest(),
+        }
+    }
+}
+
+/// The fixed size start of every record. The lengths and timestamp get a
+/// checksum of their own, so a corrupted length is caught before it is used
+/// to read the rest of the record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RecordHeader {
+    pub checksum: u64,
+    pub key_len: u32,
+    pub value_len: u32,
+    /// Milliseconds since the Unix epoch when the record was written, or 0
+    /// for records migrated from before format version 3
+    pub timestamp: u64,
+}
+
+impl RecordHeader {
+    fn header_checksum(&self) -> u32 {
+        let mut header = [0; 16];
+        header[..4].copy_from_slice(&self.key_len.to_le_bytes());
+        header[4..8].copy_from_slice(&self.value_len.to_le_bytes());
+        header[8..].copy_from_slice(&self.timestamp.to_le_bytes());
+        crc32::checksum_castagnoli(&header)
+    }
+
+    pub(crate) fn read<R: Read + ?Sized>(record: &mut R, position: u64) -> io::Result<RecordHeader> {
+        let checksum = record.read_u64::<LittleEndian>()?;
+        let key_len = record.read_u32::<LittleEndian>()?;
+        let value_len = record.read_u32::<LittleEndian>()?;
+        let timestamp = record.read_u64::<LittleEndian>()?;
+        let saved = record.read_u32::<LittleEndian>()?;
+
+        let header = RecordHeader { checksum, key_len, value_len, timestamp };
+        let computed = header.header_checksum();
+        if computed != saved {
+            return Err(corruption(position, computed as u64, saved as u64));
+        }
+        Ok(header)
+    }
+
+    pub(crate) fn write<W: Write + ?Sized>(&self, record: &mut W) -> io::Result<()> {
+        record.write_u64::<LittleEndian>(self.checksum)?;
+        record.write_u32::<LittleEndian>(self.key_len)?;
+        record.write_u32::<LittleEndian>(self.value_len)?;
+        record.write_u64::<LittleEndian>(self.timestamp)?;
+        record.write_u32::<LittleEndian>(self.header_checksum())
+    }
+}
+
+/// The current time as stored in record headers.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A checksum that didn't match, as opposed to other invalid data.
+#[derive(Debug)]
+struct Corruption(String);
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Corruption {}
+
+pub(crate) fn corruption(position: u64, computed: u64, saved: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        Corruption(format!(
+            "Data corruption encountered in record at {} ({:016x} != {:016x})",
+            position, computed, saved
+        ))
+    )
+}
+
+/// Whether `err` came from a checksum mismatch.
+pub(crate) fn is_corruption(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<Corruption>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActionKV, StoreOptions};
+
+    #[test]
+    fn every_checksum_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        for checksum in [Checksum::Crc32, Checksum::Crc32c, Checksum::XxHash64] {
+            let path = dir.path().join(format!("{:?}.akv", checksum));
+            let mut store = ActionKV::open_with(&path, &StoreOptions { checksum, ..StoreOptions::default() }).unwrap();
+            store.insert(b"k", b"v").unwrap();
+            drop(store);
+
+            // The checksum comes from the header, not the options
+            let mut store = ActionKV::open(&path).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.checksum, checksum);
+            assert_eq!(store.get(b"k").unwrap(), Some(b"v".to_vec()));
+        }
+    }
+
+    #[test]
+    fn corrupted_lengths_are_caught() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lengths.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"first").unwrap();
+        store.insert(b"b", b"second").unwrap();
+        drop(store);
+        let original = std::fs::read(&path).unwrap();
+        let key_len_at = crate::migrate::FILE_HEADER_LEN as usize + 8;
+
+        // A huge length is caught before anything is allocated for it
+        let mut bytes = original.clone();
+        bytes[key_len_at + 4..key_len_at + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(store);
+
+        // A record running past the end of the file reads as a torn write
+        let mut record = &original[key_len_at - 8..];
+        let err = ActionKV::process_record(&mut record, Checksum::Crc32, 0, crate::RECORD_HEADER_LEN + 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // The record checksum covers the lengths too
+        assert_ne!(Checksum::Crc32.record(b"a", b"first"), Checksum::Crc32.record(b"af", b"irst"));
+    }
+}
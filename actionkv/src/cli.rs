@@ -0,0 +1,127 @@
+//! Argument handling shared by the akv_mem and akv_disk tools, which each
+//! include it as a module of their own. Bad arguments panic with the tool's
+//! usage text, as in the tools themselves.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use libactionkv::encoding::Encoding;
+use libactionkv::{ActionKV, ByteStr, ByteString};
+
+/// How keys and values are read from the command line and printed.
+#[derive(Debug)]
+pub struct ValueIo {
+    key_encoding: Encoding,
+    value_encoding: Encoding,
+    value_file: Option<String>,
+    value_stdin: bool,
+    usage: &'static str,
+}
+
+impl ValueIo {
+    /// Removes the encoding and value source flags from `args`.
+    pub fn take(args: &mut Vec<String>, usage: &'static str) -> ValueIo {
+        let encoding = |name: Option<String>| name.map_or(Encoding::Utf8, |name| Encoding::from_name(&name).expect(usage));
+        ValueIo {
+            key_encoding: encoding(take_flag(args, "--key-encoding", usage)),
+            value_encoding: encoding(take_flag(args, "--value-encoding", usage)),
+            value_file: take_flag(args, "--value-file", usage),
+            value_stdin: take_switch(args, "--value-stdin"),
+            usage,
+        }
+    }
+
+    pub fn key(&self, arg: Option<&String>) -> ByteString {
+        self.key_encoding.decode(arg.expect(self.usage)).expect("Invalid key")
+    }
+
+    /// The value to write, from the value file, stdin or `arg`.
+    pub fn value(&self, arg: Option<&String>) -> ByteString {
+        if let Some(path) = &self.value_file {
+            return fs::read(path).expect("Unable to read value file");
+        }
+        if self.value_stdin {
+            let mut value = ByteString::new();
+            io::stdin().lock().read_to_end(&mut value).expect("Unable to read value from stdin");
+            return value;
+        }
+        self.value_encoding.decode(arg.expect(self.usage)).expect("Invalid value")
+    }
+
+    /// Prints `value`, or writes it to the value file exactly as stored.
+    pub fn print_value(&self, value: &ByteStr) {
+        match &self.value_file {
+            Some(path) => fs::write(path, value).expect("Unable to write value file"),
+            None => println!("{}", self.value_encoding.encode(value)),
+        }
+    }
+
+    /// `value` as text in the value encoding, for output mixed with other text.
+    // Only akv_mem mixes values into its output
+    #[allow(dead_code)]
+    pub fn encode_value(&self, value: &ByteStr) -> String {
+        self.value_encoding.encode(value)
+    }
+
+    /// Reports that `key` has no value, writing it in the key encoding.
+    pub fn print_not_found(&self, key: &ByteStr) {
+        eprintln!("{} not found", self.key_encoding.encode(key));
+    }
+}
+
+/// Removes `flag` and the argument after it from `args`, returning the
+/// argument.
+pub fn take_flag(args: &mut Vec<String>, flag: &str, usage: &str) -> Option<String> {
+    let at = args.iter().position(|arg| arg == flag)?;
+    let value = args.get(at + 1).expect(usage).clone();
+    args.drain(at..at + 2);
+    Some(value)
+}
+
+/// Removes `flag` from `args`, returning whether it was there.
+pub fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|arg| arg == flag) {
+        Some(at) => {
+            args.remove(at);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Runs the commands that manage column families or act on one. Returns
+/// false if there was nothing to do.
+pub fn run_cf_command(store: &mut ActionKV, cf: Option<&str>, action: &str, args: &[String], values: &ValueIo) -> bool {
+    let usage = values.usage;
+    match action {
+        "create-cf" => store.create_cf(args.get(3).expect(usage)).expect("Unable to create column family"),
+        "drop-cf" => store.drop_cf(args.get(3).expect(usage)).expect("Unable to drop column family"),
+        "list-cf" => {
+            for name in store.column_families() {
+                println!("{}", name);
+            }
+        },
+        _ => {
+            let name = match cf {
+                None => return false,
+                Some(name) => name,
+            };
+            let mut cf = store.cf(name).expect("Unable to open column family");
+            let key = values.key(args.get(3));
+            let key = key.as_slice();
+            match action {
+                "get" => match cf.get(key).expect("Failed to get") {
+                    None => values.print_not_found(key),
+                    Some(value) => values.print_value(&value),
+                },
+                "delete" => cf.delete(key).unwrap(),
+                "insert" | "update" => {
+                    let value = values.value(args.get(4));
+                    cf.insert(key, &value).unwrap();
+                },
+                _ => eprintln!("{}", usage),
+            }
+        },
+    }
+    true
+}
@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::Path;
+use crate::checksum::RecordHeader;
+use crate::cf::parse_cf_key;
+use crate::{bloom, ActionKV, ByteString, KeyValuePair, RECORD_HEADER_LEN};
+
+// A key and the column family it is in, `None` for the store itself
+type CfKey = (Option<String>, ByteString);
+
+/// How one key differs between two stores. `cf` names the column family
+/// the key is in, or is `None` for keys of the store itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Live in the second store only
+    Added { cf: Option<String>, key: ByteString, value: ByteString },
+    /// Live in the first store only
+    Removed { cf: Option<String>, key: ByteString, value: ByteString },
+    Changed { cf: Option<String>, key: ByteString, old: ByteString, new: ByteString },
+}
+
+impl Change {
+    pub fn cf(&self) -> Option<&str> {
+        match self {
+            Change::Added { cf, .. } | Change::Removed { cf, .. } | Change::Changed { cf, .. } => cf.as_deref(),
+        }
+    }
+
+    pub fn key(&self) -> &ByteString {
+        match self {
+            Change::Added { key, .. } | Change::Removed { key, .. } | Change::Changed { key, .. } => key,
+        }
+    }
+}
+
+/// Which value `merge` keeps for a key that is live in both stores with
+/// different values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    PreferA,
+    PreferB,
+    /// The value written last, going by the timestamps of the records.
+    /// Ties go to B, and records migrated from before format version 3,
+    /// which have no timestamp, lose to any other.
+    ///
+    /// Record positions only order the writes within one store, so the
+    /// stores are compared by wall-clock time instead. If the machines that
+    /// wrote them had clocks out of step, the older write can win.
+    LastWriter,
+}
+
+/// Compares the live keys and values of two stores and of their column
+/// families, sorted by key with the store's own keys first. Deleted keys
+/// count as absent, and so do the keys of a column family one store lacks.
+pub fn diff(a: &mut ActionKV, b: &mut ActionKV) -> io::Result<Vec<Change>> {
+    let a = live_pairs(a)?;
+    let mut b = live_pairs(b)?;
+
+    let mut changes = Vec::new();
+    for ((cf, key), old) in a {
+        match b.remove(&(cf.clone(), key.clone())) {
+            None => changes.push(Change::Removed { cf, key, value: old }),
+            Some(new) if new != old => changes.push(Change::Changed { cf, key, old, new }),
+            Some(_) => {},
+        }
+    }
+    changes.extend(b.into_iter().map(|((cf, key), value)| Change::Added { cf, key, value }));
+    changes.sort_by(|x, y| (x.cf(), x.key()).cmp(&(y.cf(), y.key())));
+    Ok(changes)
+}
+
+/// Writes a new store at `out` holding every key live in either store,
+/// settling conflicts with `policy`, and returns the number of conflicts.
+/// The new store holds one record per key, as if freshly compacted, and
+/// every column family of either store.
+///
+/// `out` must not exist yet.
+pub fn merge(a: &mut ActionKV, b: &mut ActionKV, out: &Path, policy: ConflictPolicy) -> io::Result<u64> {
+    OpenOptions::new().write(true).create_new(true).open(out)?;
+    let mut merged = ActionKV::open(out)?;
+
+    let times = match policy {
+        ConflictPolicy::LastWriter => Some((last_write_times(a)?, last_write_times(b)?)),
+        _ => None,
+    };
+    let mut pairs = live_pairs(a)?;
+    let mut conflicts = 0;
+    for (key, b_value) in live_pairs(b)? {
+        let a_value = match pairs.get(&key) {
+            None => {
+                pairs.insert(key, b_value);
+                continue;
+            },
+            Some(a_value) if *a_value == b_value => continue,
+            Some(_) => {
+                conflicts += 1;
+                pairs.get_mut(&key).unwrap()
+            },
+        };
+        let take_b = match (&times, policy) {
+            (_, ConflictPolicy::PreferA) => false,
+            (_, ConflictPolicy::PreferB) => true,
+            (Some((a_times, b_times)), ConflictPolicy::LastWriter) => b_times.get(&key) >= a_times.get(&key),
+            (None, ConflictPolicy::LastWriter) => unreachable!(),
+        };
+        if take_b {
+            *a_value = b_value;
+        }
+    }
+
+    let mut names: Vec<String> = a.column_families().into_iter().map(String::from).collect();
+    names.extend(b.column_families().into_iter().map(String::from));
+    names.sort_unstable();
+    names.dedup();
+    for name in names {
+        merged.create_cf(&name)?;
+    }
+    for ((cf, key), value) in pairs {
+        match cf {
+            Some(name) => merged.cf(&name)?.insert(&key, &value)?,
+            None => merged.insert(&key, &value)?,
+        }
+    }
+    merged.sync()?;
+    Ok(conflicts)
+}
+
+/// The live pairs of the store and of each of its column families.
+fn live_pairs(store: &mut ActionKV) -> io::Result<BTreeMap<CfKey, ByteString>> {
+    let mut pairs: BTreeMap<CfKey, ByteString> = store.scan(b"")?
+        .into_iter()
+        .map(|KeyValuePair { key, value }| ((None, key), value))
+        .collect();
+    let names: Vec<String> = store.column_families().into_iter().map(String::from).collect();
+    for name in names {
+        for KeyValuePair { key, value } in store.cf(&name)?.scan(b"")? {
+            pairs.insert((Some(name.clone()), key), value);
+        }
+    }
+    Ok(pairs)
+}
+
+/// The timestamp of the last record written for each key.
+fn last_write_times(store: &mut ActionKV) -> io::Result<HashMap<CfKey, u64>> {
+    let mut times = HashMap::new();
+    let end = store.file.metadata()?.len();
+    let mut f = BufReader::new(&mut store.file);
+    let mut position = f.seek(SeekFrom::Start(store.data_start))?;
+    while position + RECORD_HEADER_LEN <= end {
+        let RecordHeader { timestamp, .. } = RecordHeader::read(&mut f, position)?;
+        f.seek_relative(-(RECORD_HEADER_LEN as i64))?;
+        let kv = match ActionKV::process_record(&mut f, store.checksum, position, end) {
+            Ok(kv) => kv,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+        // Large values, merges and column families are filed under
+        // internal keys
+        let key = match parse_cf_key(&kv.key) {
+            Some((name, key)) => (Some(name.to_string()), key.to_vec()),
+            None => (None, bloom::bloom_key(&kv.key).to_vec()),
+        };
+        times.insert(key, timestamp);
+        position = f.stream_position()?;
+    }
+    Ok(times)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn store(dir: &Path, name: &str, writes: &[(&[u8], &[u8])]) -> ActionKV {
+        let mut store = ActionKV::open(&dir.join(name)).unwrap();
+        store.load().unwrap();
+        for (key, value) in writes {
+            store.insert(key, value).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn diffs_live_views() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = store(dir.path(), "a.akv", &[(b"same", b"1"), (b"changed", b"old"), (b"removed", b"x"), (b"deleted", b"x")]);
+        let mut b = store(dir.path(), "b.akv", &[(b"changed", b"new"), (b"same", b"1"), (b"added", b"y"), (b"deleted", b"x"), (b"deleted", b"")]);
+
+        let changes = diff(&mut a, &mut b).unwrap();
+        assert_eq!(changes, vec![
+            Change::Added { cf: None, key: b"added".to_vec(), value: b"y".to_vec() },
+            Change::Changed { cf: None, key: b"changed".to_vec(), old: b"old".to_vec(), new: b"new".to_vec() },
+            Change::Removed { cf: None, key: b"deleted".to_vec(), value: b"x".to_vec() },
+            Change::Removed { cf: None, key: b"removed".to_vec(), value: b"x".to_vec() },
+        ]);
+    }
+
+    #[test]
+    fn diffs_and_merges_column_families() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = store(dir.path(), "a.akv", &[(b"k", b"1")]);
+        let mut b = store(dir.path(), "b.akv", &[(b"k", b"1")]);
+        a.create_cf("users").unwrap();
+        a.cf("users").unwrap().insert(b"k", b"a").unwrap();
+        a.create_cf("empty").unwrap();
+        b.create_cf("users").unwrap();
+        b.cf("users").unwrap().insert(b"k", b"b").unwrap();
+        b.create_cf("orders").unwrap();
+        b.cf("orders").unwrap().insert(b"o", b"1").unwrap();
+
+        let changes = diff(&mut a, &mut b).unwrap();
+        assert_eq!(changes, vec![
+            Change::Added { cf: Some("orders".to_string()), key: b"o".to_vec(), value: b"1".to_vec() },
+            Change::Changed { cf: Some("users".to_string()), key: b"k".to_vec(), old: b"a".to_vec(), new: b"b".to_vec() },
+        ]);
+
+        let out = dir.path().join("out.akv");
+        assert_eq!(merge(&mut a, &mut b, &out, ConflictPolicy::PreferA).unwrap(), 1);
+        let mut merged = ActionKV::open(&out).unwrap();
+        merged.load().unwrap();
+        assert_eq!(merged.column_families(), vec!["empty", "orders", "users"]);
+        assert_eq!(merged.get(b"k").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(merged.cf("users").unwrap().get(b"k").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(merged.cf("orders").unwrap().get(b"o").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn merges_with_each_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        // "k" was written last in a and "j" last in b, though "k" is at the
+        // same place in both logs
+        let mut a = store(dir.path(), "a.akv", &[(b"j", b"a"), (b"only_a", b"1")]);
+        let mut b = store(dir.path(), "b.akv", &[(b"only_b", b"2"), (b"pad", b"3"), (b"k", b"b")]);
+        thread::sleep(Duration::from_millis(5));
+        a.insert(b"k", b"a").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        b.insert(b"j", b"b").unwrap();
+
+        let cases = [
+            (ConflictPolicy::PreferA, b"a", b"a"),
+            (ConflictPolicy::PreferB, b"b", b"b"),
+            (ConflictPolicy::LastWriter, b"b", b"a"),
+        ];
+        for (i, (policy, j, k)) in cases.into_iter().enumerate() {
+            let out = dir.path().join(format!("out{}.akv", i));
+            assert_eq!(merge(&mut a, &mut b, &out, policy).unwrap(), 2);
+
+            let mut merged = ActionKV::open(&out).unwrap();
+            merged.load().unwrap();
+            assert_eq!(merged.get(b"j").unwrap(), Some(j.to_vec()), "{:?}", policy);
+            assert_eq!(merged.get(b"k").unwrap(), Some(k.to_vec()), "{:?}", policy);
+            assert_eq!(merged.scan(b"").unwrap().len(), 5);
+            assert_eq!(merged.stats().unwrap().total_records, 5);
+        }
+
+        let out = dir.path().join("out0.akv");
+        assert_eq!(merge(&mut a, &mut b, &out, ConflictPolicy::PreferA).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+}
@@ -0,0 +1,85 @@
+use std::io;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use crate::{ByteStr, ByteString};
+
+/// How keys and values are written as text, on the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// The text's own bytes. Output that isn't valid UTF-8 has the invalid
+    /// parts replaced, so it can't always be read back.
+    #[default]
+    Utf8,
+    /// Two hex digits per byte
+    Hex,
+    /// Standard base64, with padding
+    Base64,
+}
+
+impl Encoding {
+    /// Looks up an encoding by the name used for it in flags.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "utf8" => Some(Encoding::Utf8),
+            "hex" => Some(Encoding::Hex),
+            "base64" => Some(Encoding::Base64),
+            _ => None,
+        }
+    }
+
+    pub fn decode(self, text: &str) -> io::Result<ByteString> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Hex => decode_hex(text).ok_or_else(|| invalid_input(format!("{:?} isn't valid hex", text))),
+            Encoding::Base64 => BASE64.decode(text).map_err(|err| invalid_input(format!("{:?} isn't valid base64: {}", text, err))),
+        }
+    }
+
+    pub fn encode(self, bytes: &ByteStr) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            Encoding::Base64 => BASE64.encode(bytes),
+        }
+    }
+}
+
+fn decode_hex(text: &str) -> Option<ByteString> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_any_bytes() {
+        let bytes: ByteString = (0..=255).collect();
+        for encoding in [Encoding::Hex, Encoding::Base64] {
+            assert_eq!(encoding.decode(&encoding.encode(&bytes)).unwrap(), bytes);
+        }
+        assert_eq!(Encoding::Hex.encode(b"\x00\xffA"), "00ff41");
+        assert_eq!(Encoding::Hex.decode("00FF41").unwrap(), b"\x00\xffA");
+        assert_eq!(Encoding::Base64.encode(b"\xff\x00"), "/wA=");
+        assert_eq!(Encoding::Utf8.decode("k\u{e9}y").unwrap(), "k\u{e9}y".as_bytes());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for bad in ["abc", "zz", "\u{e9}\u{e9}"] {
+            assert_eq!(Encoding::Hex.decode(bad).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(Encoding::Base64.decode("not base64!").is_err());
+        assert_eq!(Encoding::from_name("rot13"), None);
+    }
+}
@@ -0,0 +1,311 @@
+//! Randomized tests of `ActionKV` against a `HashMap` model, and a file
+//! wrapper that injects the damage a crash can leave behind.
+
+use std::io;
+use std::io::Write;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+
+/// What goes wrong while writing to a `FaultyFile`. Each fault happens at a
+/// byte offset in the file, and the process is taken to have died then, so
+/// every later write fails.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Fault {
+    /// Nothing from the offset on reaches the disk
+    Crash(u64),
+    /// The write crossing the offset stops there and reports a short write
+    ShortWrite(u64),
+    /// The write crossing the offset reports success, but the disk ends up
+    /// with garbage from the offset to the end of that write
+    TornWrite(u64),
+}
+
+pub(crate) struct FaultyFile<W> {
+    inner: W,
+    written: u64,
+    fault: Fault,
+    dead: bool,
+    garbage: StdRng,
+}
+
+impl<W: Write> FaultyFile<W> {
+    pub(crate) fn new(inner: W, fault: Fault) -> Self {
+        FaultyFile { inner, written: 0, fault, dead: false, garbage: StdRng::seed_from_u64(0) }
+    }
+}
+
+impl<W: Write> Write for FaultyFile<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.dead {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "crashed"));
+        }
+        let at = match self.fault {
+            Fault::Crash(at) | Fault::ShortWrite(at) | Fault::TornWrite(at) => at,
+        };
+        let end = self.written + buf.len() as u64;
+        if end <= at {
+            self.inner.write_all(buf)?;
+            self.written = end;
+            return Ok(buf.len());
+        }
+
+        let intact = at.saturating_sub(self.written) as usize;
+        self.inner.write_all(&buf[..intact])?;
+        self.written += intact as u64;
+        self.dead = true;
+        match self.fault {
+            Fault::Crash(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "crashed")),
+            Fault::ShortWrite(_) => Ok(intact),
+            Fault::TornWrite(_) => {
+                let mut garbage = vec![0; buf.len() - intact];
+                self.garbage.fill_bytes(&mut garbage);
+                self.inner.write_all(&garbage)?;
+                self.written = end;
+                Ok(buf.len())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use rand::Rng;
+    use crate::{ActionKV, ByteString};
+
+    #[derive(Debug)]
+    enum Op {
+        Insert(ByteString, ByteString),
+        InsertChunked(ByteString, ByteString),
+        Delete(ByteString),
+        Compact,
+        Reopen,
+    }
+
+    fn random_op<R: Rng>(rng: &mut R, with_reopen: bool) -> Op {
+        let key = format!("k{}", rng.gen_range(0..16)).into_bytes();
+        let mut value = vec![0; rng.gen_range(1..48)];
+        rng.fill_bytes(&mut value);
+        match rng.gen_range(0..20) {
+            0..=10 => Op::Insert(key, value),
+            11..=12 => Op::InsertChunked(key, value),
+            13..=16 => Op::Delete(key),
+            17 => Op::Compact,
+            _ if with_reopen => Op::Reopen,
+            _ => Op::Insert(key, value),
+        }
+    }
+
+    /// Applies `op` to the store and the model alike.
+    fn apply(store: &mut ActionKV, model: &mut HashMap<ByteString, ByteString>, op: &Op) {
+        match op {
+            Op::Insert(key, value) => {
+                store.insert(key, value).unwrap();
+                model.insert(key.clone(), value.clone());
+            },
+            Op::InsertChunked(key, value) => {
+                store.insert_chunked(key, &value[..], 16).unwrap();
+                model.insert(key.clone(), value.clone());
+            },
+            Op::Delete(key) => {
+                store.delete(key).unwrap();
+                model.insert(key.clone(), ByteString::new());
+            },
+            Op::Compact => {
+                store.compact().unwrap();
+                model.retain(|_, value| !value.is_empty());
+            },
+            Op::Reopen => unreachable!("reopen replaces the store"),
+        }
+    }
+
+    fn reopen(store: ActionKV, path: &Path) -> ActionKV {
+        // The old store has to let go of its lock first
+        drop(store);
+        let mut store = ActionKV::open(path).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    fn check(store: &mut ActionKV, model: &HashMap<ByteString, ByteString>, context: &str) {
+        for i in 0..16 {
+            let key = format!("k{}", i).into_bytes();
+            assert_eq!(store.get(&key).unwrap(), model.get(&key).cloned(), "{} k{}", context, i);
+        }
+    }
+
+    #[test]
+    fn matches_a_hashmap_model() {
+        for seed in 0..20 {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("model.akv");
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut store = ActionKV::open(&path).unwrap();
+            store.load().unwrap();
+            let mut model = HashMap::new();
+
+            for step in 0..200 {
+                let op = random_op(&mut rng, true);
+                match op {
+                    Op::Reopen => store = reopen(store, &path),
+                    _ => apply(&mut store, &mut model, &op),
+                }
+                check(&mut store, &model, &format!("seed {} step {} after {:?}:", seed, step, op));
+            }
+        }
+    }
+
+    #[test]
+    fn load_recovers_the_last_whole_write() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let dir = tempfile::tempdir().unwrap();
+
+        // Run a history of writes, noting the file length and the model after
+        // each one
+        let golden_path = dir.path().join("golden.akv");
+        let mut store = ActionKV::open(&golden_path).unwrap();
+        store.load().unwrap();
+        let mut model = HashMap::new();
+        let mut commits = vec![(fs::metadata(&golden_path).unwrap().len(), model.clone())];
+        for _ in 0..40 {
+            let op = loop {
+                match random_op(&mut rng, false) {
+                    Op::Compact => continue,
+                    op => break op,
+                }
+            };
+            apply(&mut store, &mut model, &op);
+            commits.push((fs::metadata(&golden_path).unwrap().len(), model.clone()));
+        }
+        drop(store);
+        let golden = fs::read(&golden_path).unwrap();
+
+        for trial in 0..120 {
+            // Fail somewhere inside one of the writes
+            let write = rng.gen_range(1..commits.len());
+            let (start, end) = (commits[write - 1].0, commits[write].0);
+            let at = rng.gen_range(start..end);
+            let fault = match trial % 3 {
+                0 => Fault::Crash(at),
+                1 => Fault::ShortWrite(at),
+                _ => Fault::TornWrite(at),
+            };
+
+            let path = dir.path().join(format!("crash{}.akv", trial));
+            {
+                let mut file = FaultyFile::new(fs::File::create(&path).unwrap(), fault);
+                // The file header, then each write in one go like `insert` does
+                file.write_all(&golden[..commits[0].0 as usize]).unwrap();
+                for window in commits.windows(2) {
+                    let bytes = &golden[window[0].0 as usize..window[1].0 as usize];
+                    if file.write(bytes).is_err() {
+                        break;
+                    }
+                }
+            }
+
+            let context = format!("trial {} with {:?} in write {}:", trial, fault, write);
+            let mut model = commits[write - 1].1.clone();
+            let mut store = ActionKV::open(&path).unwrap();
+            store.load().unwrap();
+            check(&mut store, &model, &context);
+            // Whole chunk records of an unfinished large value may be kept
+            let len = fs::metadata(&path).unwrap().len();
+            assert!(start <= len && len <= at, "{} cut to {}", context, len);
+
+            // New writes land after the recovered prefix, not behind the damage
+            apply(&mut store, &mut model, &Op::Insert(b"k0".to_vec(), b"after".to_vec()));
+            let mut store = reopen(store, &path);
+            check(&mut store, &model, &context);
+        }
+    }
+
+    #[test]
+    fn damage_before_intact_records_is_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("middle.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"first").unwrap();
+        store.insert(b"b", b"second").unwrap();
+        drop(store);
+
+        let mut bytes = fs::read(&path).unwrap();
+        let first_value = crate::migrate::FILE_HEADER_LEN + crate::RECORD_HEADER_LEN + 1;
+        bytes[first_value as usize] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(store);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn bit_flip_in_the_last_record_is_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flip.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"first").unwrap();
+        store.insert(b"b", b"second").unwrap();
+        drop(store);
+
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.metrics().checksum_failures(), 1);
+        assert_eq!(store.metrics().dropped_tail_bytes(), crate::RECORD_HEADER_LEN + 7);
+    }
+
+    #[test]
+    fn damage_is_found_past_the_scan_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("window.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"big", &vec![0; 3 * crate::TAIL_SCAN_BUF_LEN]).unwrap();
+        store.insert(b"after", b"intact").unwrap();
+        drop(store);
+
+        // With the big record's header gone, the scan has to cross its value
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[crate::migrate::FILE_HEADER_LEN as usize + 9] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(store);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn intact_records_bigger_than_the_scan_window_are_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big_after.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"first").unwrap();
+        store.insert(b"big", &vec![7; 3 * crate::TAIL_SCAN_BUF_LEN]).unwrap();
+        drop(store);
+
+        let mut bytes = fs::read(&path).unwrap();
+        let first_value = crate::migrate::FILE_HEADER_LEN + crate::RECORD_HEADER_LEN + 1;
+        bytes[first_value as usize] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,273 @@
+use std::io;
+use std::io::Cursor;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use crate::{ActionKV, ByteString};
+
+/// Serves `store` over HTTP until the server is shut down, one request at a
+/// time.
+///
+/// - `GET /kv/{key}` returns the value as the body, streamed from disk
+/// - `PUT /kv/{key}` stores the body as the value
+/// - `DELETE /kv/{key}` deletes the key
+/// - `GET /kv?prefix=P` returns the live pairs whose key starts with `P` as a
+///   JSON array of `{"key": ..., "value": ...}`, both base64 encoded
+/// - `GET /stats` returns store statistics as JSON
+/// - `GET /metrics` returns the store's metrics in the Prometheus text format
+/// - `GET /health` returns `{"status": "ok"}`
+///
+/// Keys in paths and query strings are percent-encoded, so any bytes can be
+/// used as a key.
+///
+/// A response that can't be sent only fails its own request, so it is
+/// logged and the server carries on.
+pub fn serve(server: &Server, store: &mut ActionKV) {
+    for request in server.incoming_requests() {
+        if let Err(err) = handle(store, request) {
+            eprintln!("Unable to respond: {}", err);
+        }
+    }
+}
+
+/// Answers one request. Store errors are reported to the client; only
+/// failures to write the response are returned.
+pub fn handle(store: &mut ActionKV, mut request: Request) -> io::Result<()> {
+    let url = request.url().to_string();
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url.as_str(), None),
+    };
+    let method = request.method().clone();
+
+    let result = match (&method, path) {
+        (Method::Get, "/health") => Ok(json_response(&json!({ "status": "ok" }))),
+        (Method::Get, "/stats") => stats(store),
+        (Method::Get, "/metrics") => Ok(metrics(store)),
+        (Method::Get, "/kv") => scan(store, query.unwrap_or("")),
+        (_, path) if path.starts_with("/kv/") => {
+            match percent_decode(&path["/kv/".len()..]) {
+                None => Ok(error_response(400, "malformed percent-encoding in key")),
+                Some(key) => match method {
+                    Method::Get => return get(store, request, &key),
+                    Method::Put => put(store, &mut request, &key),
+                    Method::Delete => store.delete(&key).map(|_| empty_response(204)),
+                    _ => Ok(error_response(405, "method not allowed")),
+                },
+            }
+        },
+        _ => Ok(error_response(404, "not found")),
+    };
+
+    let response = result.unwrap_or_else(|err| {
+        let status = match err.kind() {
+            io::ErrorKind::InvalidInput => 400,
+            io::ErrorKind::PermissionDenied => 403,
+            _ => 500,
+        };
+        error_response(status, &err.to_string())
+    });
+    request.respond(response)
+}
+
+type BufferedResponse = Response<Cursor<Vec<u8>>>;
+
+fn get(store: &mut ActionKV, request: Request, key: &[u8]) -> io::Result<()> {
+    let reader = match store.get_reader(key) {
+        Ok(reader) => reader,
+        Err(err) => return request.respond(error_response(500, &err.to_string())),
+    };
+    match reader {
+        // Deleted keys are stored as empty values
+        Some(reader) if !reader.is_empty() => {
+            let len = reader.len() as usize;
+            let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/octet-stream"[..]).unwrap();
+            request.respond(Response::new(StatusCode(200), vec![content_type], reader, Some(len), None))
+        },
+        _ => request.respond(error_response(404, "key not found")),
+    }
+}
+
+fn put(store: &mut ActionKV, request: &mut Request, key: &[u8]) -> io::Result<BufferedResponse> {
+    // Without a length the body could be any size, so it is chunked
+    match request.body_length() {
+        Some(len) => store.insert_from_reader(key, request.as_reader(), len as u64)?,
+        None => store.insert_large(key, request.as_reader())?,
+    }
+    Ok(empty_response(204))
+}
+
+fn scan(store: &mut ActionKV, query: &str) -> io::Result<BufferedResponse> {
+    let mut prefix = ByteString::new();
+    for pair in query.split('&') {
+        if let Some(value) = pair.strip_prefix("prefix=") {
+            match percent_decode(value) {
+                Some(value) => prefix = value,
+                None => return Ok(error_response(400, "malformed percent-encoding in prefix")),
+            }
+        }
+    }
+
+    let pairs: Vec<_> = store.scan(&prefix)?
+        .into_iter()
+        .map(|kv| json!({ "key": BASE64.encode(kv.key), "value": BASE64.encode(kv.value) }))
+        .collect();
+    Ok(json_response(&json!(pairs)))
+}
+
+fn stats(store: &mut ActionKV) -> io::Result<BufferedResponse> {
+    let stats = store.stats()?;
+    Ok(json_response(&json!({
+        "total_records": stats.total_records,
+        "live_keys": stats.live_keys,
+        "file_bytes": stats.file_bytes,
+        "live_bytes": stats.live_bytes,
+        "stale_bytes": stats.stale_bytes,
+        "space_amplification": stats.space_amplification(),
+        "compaction_recommended": stats.compaction_recommended(),
+        "cache_hits": stats.cache.map(|cache| cache.hits),
+        "cache_misses": stats.cache.map(|cache| cache.misses),
+    })))
+}
+
+fn metrics(store: &ActionKV) -> BufferedResponse {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..]).unwrap();
+    Response::from_data(store.metrics().to_prometheus()).with_header(content_type)
+}
+
+fn json_response(body: &serde_json::Value) -> BufferedResponse {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    Response::from_data(body.to_string()).with_header(content_type)
+}
+
+fn error_response(status: u16, message: &str) -> BufferedResponse {
+    json_response(&json!({ "error": message })).with_status_code(status)
+}
+
+fn empty_response(status: u16) -> BufferedResponse {
+    Response::from_data(Vec::new()).with_status_code(status)
+}
+
+/// Decodes `%XX` escapes. `+` is left alone, since keys can contain it.
+pub fn percent_decode(encoded: &str) -> Option<ByteString> {
+    let mut decoded = ByteString::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    /// Sends one request and returns the status code and body.
+    fn send(addr: &str, method: &str, path: &str, body: &[u8]) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+            method, path, body.len()
+        ).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn serves_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("http.akv")).unwrap();
+        store.load().unwrap();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap().to_string();
+        thread::spawn(move || serve(&server, &mut store));
+
+        assert_eq!(send(&addr, "GET", "/health", b""), (200, r#"{"status":"ok"}"#.to_string()));
+        assert_eq!(send(&addr, "PUT", "/kv/a%00b", b"binary key").0, 204);
+        assert_eq!(send(&addr, "PUT", "/kv/a%2Fc", b"slash").0, 204);
+        assert_eq!(send(&addr, "GET", "/kv/a%00b", b""), (200, "binary key".to_string()));
+        assert_eq!(send(&addr, "GET", "/kv/missing", b"").0, 404);
+
+        let (status, body) = send(&addr, "GET", "/kv?prefix=a%2F", b"");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"[{"key":"YS9j","value":"c2xhc2g="}]"#);
+
+        assert_eq!(send(&addr, "DELETE", "/kv/a%2Fc", b"").0, 204);
+        assert_eq!(send(&addr, "GET", "/kv/a%2Fc", b"").0, 404);
+
+        let (status, body) = send(&addr, "GET", "/stats", b"");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""live_keys":1"#), "{}", body);
+
+        let (status, body) = send(&addr, "GET", "/metrics", b"");
+        assert_eq!(status, 200);
+        assert!(body.contains("akv_inserts_total 2\n") && body.contains("akv_deletes_total 1\n"), "{}", body);
+
+        assert_eq!(send(&addr, "PUT", "/kv/%ffakv%00large%00x", b"v").0, 400);
+        assert_eq!(send(&addr, "GET", "/nope", b"").0, 404);
+    }
+
+    #[test]
+    fn serves_merged_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("merged.akv")).unwrap();
+        store.load().unwrap();
+        store.insert(b"log", b"abc").unwrap();
+        store.merge(b"log", "append", b"def").unwrap();
+        store.merge(b"new", "append", b"xyz").unwrap();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap().to_string();
+        thread::spawn(move || serve(&server, &mut store));
+
+        assert_eq!(send(&addr, "GET", "/kv/log", b""), (200, "abcdef".to_string()));
+        assert_eq!(send(&addr, "GET", "/kv/new", b""), (200, "xyz".to_string()));
+    }
+
+    #[test]
+    fn failed_responses_dont_stop_the_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("failing.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"k", &vec![0; 64 * 1024]).unwrap();
+
+        // The damage is only found once the body is partly sent
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap().to_string();
+        thread::spawn(move || serve(&server, &mut store));
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        write!(stream, "GET /kv/k HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        assert_eq!(send(&addr, "GET", "/health", b"").0, 200);
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b+c"), Some(b"a b+c".to_vec()));
+        assert_eq!(percent_decode("%ff%00"), Some(vec![0xff, 0]));
+        assert_eq!(percent_decode("%f"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::time::Instant;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::{internal_key, parse_internal_key, ActionKV, ByteStr, ByteString, RECORD_HEADER_LEN};
+
+/// Size of each chunk written by `insert_large`.
+pub const LARGE_VALUE_CHUNK_LEN: usize = 64 * 1024 * 1024;
+
+pub(crate) const MANIFEST_KIND: &ByteStr = b"large";
+pub(crate) const CHUNK_KIND: &ByteStr = b"chunk";
+
+// Marks the last chunk of a value in place of the next chunk's position
+const NO_NEXT_CHUNK: u64 = u64::MAX;
+
+// Chunk values start with the position of the next chunk
+pub(crate) const CHUNK_LINK_LEN: usize = 8;
+
+/// Describes a chunked value. It is stored under the key's manifest key, so
+/// whichever of the key's plain record and its manifest was written last
+/// holds the current value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub total_len: u64,
+    pub first_chunk: u64,
+}
+
+impl Manifest {
+    fn encode(&self) -> ByteString {
+        let mut buf = ByteString::with_capacity(16);
+        buf.write_u64::<LittleEndian>(self.total_len).unwrap();
+        buf.write_u64::<LittleEndian>(self.first_chunk).unwrap();
+        buf
+    }
+
+    pub(crate) fn decode(value: &ByteStr) -> io::Result<Manifest> {
+        let mut cursor = Cursor::new(value);
+        let total_len = cursor.read_u64::<LittleEndian>()?;
+        let first_chunk = cursor.read_u64::<LittleEndian>()?;
+        Ok(Manifest { total_len, first_chunk })
+    }
+}
+
+pub(crate) fn chunk_next(value: &ByteStr) -> io::Result<Option<u64>> {
+    let next = Cursor::new(value).read_u64::<LittleEndian>()?;
+    Ok(if next == NO_NEXT_CHUNK { None } else { Some(next) })
+}
+
+/// Rewrites the chunk positions held in the value of an internal record,
+/// for when a migration has moved the chunks. `moved` maps each chunk's old
+/// position to its new one.
+pub(crate) fn remap_positions(key: &ByteStr, value: &mut [u8], moved: &HashMap<u64, u64>) -> io::Result<()> {
+    // Chunks start with the next chunk's position and manifests hold the
+    // first chunk's position after the total length
+    let at = match parse_internal_key(key) {
+        Some((CHUNK_KIND, _)) => 0,
+        Some((MANIFEST_KIND, _)) => 8,
+        _ => return Ok(()),
+    };
+    let field = value.get_mut(at..at + 8).ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidData,
+        "large value record is too short to hold a chunk position"
+    ))?;
+
+    let old = Cursor::new(&*field).read_u64::<LittleEndian>()?;
+    if old != NO_NEXT_CHUNK {
+        let new = moved.get(&old).copied().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("large value refers to a chunk at {} that doesn't exist", old)
+        ))?;
+        field.copy_from_slice(&new.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Streams a value back one chunk at a time, checking each chunk's CRC as
+/// it is read. Plain values are served from a single in-memory buffer.
+pub struct LargeValueReader<'a> {
+    store: &'a mut ActionKV,
+    chunk: Cursor<ByteString>,
+    next_chunk: Option<u64>,
+    remaining: u64,
+}
+
+impl<'a> LargeValueReader<'a> {
+    /// Bytes of the value not yet read.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl Read for LargeValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.remaining = self.remaining.saturating_sub(n as u64);
+                return Ok(n);
+            }
+
+            let position = match self.next_chunk {
+                None => return Ok(0),
+                Some(position) => position,
+            };
+            let mut value = self.store.get_at(position)?.value;
+            self.next_chunk = chunk_next(&value)?;
+            let data = value.split_off(CHUNK_LINK_LEN);
+            self.chunk = Cursor::new(data);
+        }
+    }
+}
+
+impl ActionKV {
+    /// Stores a value of any size read from `value`, split into linked chunk
+    /// records of `LARGE_VALUE_CHUNK_LEN` bytes. Only one chunk is held in
+    /// memory at a time.
+    pub fn insert_large<R: Read>(&mut self, key: &ByteStr, value: R) -> io::Result<()> {
+        self.insert_chunked(key, value, LARGE_VALUE_CHUNK_LEN)
+    }
+
+    pub(crate) fn insert_chunked<R: Read>(&mut self, key: &ByteStr, mut value: R, chunk_len: usize) -> io::Result<()> {
+        ActionKV::check_user_key(key)?;
+        let started = Instant::now();
+        let chunk_key = internal_key(CHUNK_KIND, key);
+
+        // Reading one chunk ahead tells us whether the current chunk is the
+        // last one before it is written
+        let mut current = read_chunk(&mut value, chunk_len)?;
+        let mut total_len = 0;
+        let mut first_chunk = None;
+        loop {
+            let next = if current.len() < chunk_len {
+                ByteString::new()
+            } else {
+                read_chunk(&mut value, chunk_len)?
+            };
+
+            let position = self.file.seek(SeekFrom::End(0))?;
+            let record_len = RECORD_HEADER_LEN + (chunk_key.len() + CHUNK_LINK_LEN + current.len()) as u64;
+            let next_chunk = if next.is_empty() { NO_NEXT_CHUNK } else { position + record_len };
+
+            let mut chunk = ByteString::with_capacity(CHUNK_LINK_LEN + current.len());
+            chunk.write_u64::<LittleEndian>(next_chunk)?;
+            chunk.extend_from_slice(&current);
+            let written = self.insert_but_ignore_index(&chunk_key, &chunk)?;
+            debug_assert_eq!(written, position);
+
+            first_chunk.get_or_insert(position);
+            total_len += current.len() as u64;
+            if next.is_empty() {
+                break;
+            }
+            current = next;
+        }
+
+        let manifest = Manifest { total_len, first_chunk: first_chunk.unwrap() };
+        let manifest_key = internal_key(MANIFEST_KIND, key);
+        let position = self.insert_but_ignore_index(&manifest_key, &manifest.encode())?;
+        self.index_key(manifest_key, position);
+        self.metrics.record_write(total_len, started);
+
+        Ok(())
+    }
+
+    /// Returns a reader over the value of `key`, whether it was stored with
+    /// `insert` or `insert_large`. Values with merges are read into memory to
+    /// apply them.
+    pub fn get_large(&mut self, key: &ByteStr) -> io::Result<Option<LargeValueReader<'_>>> {
+        if !self.merges.contains_key(key) {
+            return self.get_large_base(key);
+        }
+        Ok(self.get_merged(key)?.map(|value| LargeValueReader {
+            remaining: value.len() as u64,
+            chunk: Cursor::new(value),
+            next_chunk: None,
+            store: self,
+        }))
+    }
+
+    /// Like `get_large`, without any merges applied.
+    pub(crate) fn get_large_base(&mut self, key: &ByteStr) -> io::Result<Option<LargeValueReader<'_>>> {
+        let reader = match self.locate(key) {
+            None => return Ok(None),
+            Some(Location::Record(position)) => {
+                let value = self.get_at(position)?.value;
+                LargeValueReader {
+                    remaining: value.len() as u64,
+                    chunk: Cursor::new(value),
+                    next_chunk: None,
+                    store: self,
+                }
+            },
+            Some(Location::Large(position)) => {
+                let manifest = Manifest::decode(&self.get_at(position)?.value)?;
+                LargeValueReader {
+                    remaining: manifest.total_len,
+                    chunk: Cursor::new(ByteString::new()),
+                    next_chunk: Some(manifest.first_chunk),
+                    store: self,
+                }
+            },
+        };
+
+        Ok(Some(reader))
+    }
+}
+
+/// Where the current value of a key lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Location {
+    Record(u64),
+    Large(u64),
+}
+
+fn read_chunk<R: Read>(reader: &mut R, chunk_len: usize) -> io::Result<ByteString> {
+    let mut chunk = ByteString::with_capacity(chunk_len);
+    reader.take(chunk_len as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_values_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.akv");
+        let value: ByteString = (0..100u32).map(|i| i as u8).collect();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert_chunked(b"big", &value[..], 16).unwrap();
+        store.insert(b"small", b"v").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        let mut reader = store.get_large(b"big").unwrap().unwrap();
+        assert_eq!(reader.remaining(), 100);
+        let mut streamed = Vec::new();
+        reader.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, value);
+        assert_eq!(store.get(b"big").unwrap(), Some(value.clone()));
+
+        let keys: Vec<_> = store.scan(b"").unwrap().into_iter().map(|kv| kv.key).collect();
+        assert_eq!(keys, vec![b"big".to_vec(), b"small".to_vec()]);
+
+        store.compact().unwrap();
+        assert_eq!(store.get(b"big").unwrap(), Some(value));
+
+        store.insert(b"big", b"plain again").unwrap();
+        assert_eq!(store.get(b"big").unwrap(), Some(b"plain again".to_vec()));
+    }
+
+    #[test]
+    fn merges_apply_to_chunked_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("merged.akv")).unwrap();
+        store.load().unwrap();
+        store.insert_chunked(b"big", &b"0123456789"[..], 4).unwrap();
+        store.merge(b"big", "append", b"ab").unwrap();
+        store.merge(b"only", "append", b"xy").unwrap();
+
+        for (key, value) in [(&b"big"[..], &b"0123456789ab"[..]), (b"only", b"xy")] {
+            let mut reader = store.get_large(key).unwrap().unwrap();
+            assert_eq!(reader.remaining(), value.len() as u64);
+            let mut streamed = Vec::new();
+            reader.read_to_end(&mut streamed).unwrap();
+            assert_eq!(streamed, value);
+        }
+        assert_eq!(store.get(b"big").unwrap(), Some(b"0123456789ab".to_vec()));
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("edges.akv")).unwrap();
+        store.load().unwrap();
+
+        for len in [0, 1, 15, 16, 17, 32] {
+            let value = vec![7u8; len];
+            store.insert_chunked(b"k", &value[..], 16).unwrap();
+            assert_eq!(store.get(b"k").unwrap(), Some(value));
+        }
+    }
+}
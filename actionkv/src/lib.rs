@@ -1,117 +1,506 @@
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::fs::{File, OpenOptions, TryLockError};
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crc::crc32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 use serde_derive::{Deserialize, Serialize};
 
+#[cfg(feature = "async")]
+pub mod async_kv;
+pub mod backup;
+pub mod bloom;
+pub mod cache;
+pub mod cf;
+pub mod diff;
+pub mod encoding;
+pub mod checksum;
+#[cfg(test)]
+mod fault;
+pub mod history;
+pub mod http;
+pub mod large;
+pub mod merge;
+pub mod metrics;
+pub mod migrate;
+pub mod raft;
+pub mod shard;
+pub mod shell;
+pub mod stats;
+pub mod stream;
+pub mod typed;
+
+#[cfg(feature = "async")]
+pub use async_kv::AsyncActionKV;
+pub use backup::RestorePoint;
+pub use bloom::BloomFilter;
+pub use cache::CacheStats;
+pub use cf::ColumnFamily;
+pub use history::AsOf;
+pub use merge::MergeOperator;
+pub use metrics::Metrics;
+pub use shard::ShardedStore;
+pub use checksum::Checksum;
+use cache::ValueCache;
+use cf::CfIndex;
+use checksum::RecordHeader;
+use large::Location;
+pub use large::LargeValueReader;
+pub use stream::ValueReader;
+pub use typed::{TypedError, TypedStore};
+
 // ByteStr is to &str what ByteString is to Vec<u8>
 pub type ByteString = Vec<u8>;
 pub type ByteStr = [u8];
 
+// checksum, key_len, value_len, timestamp and the checksum of the header
+pub const RECORD_HEADER_LEN: u64 = 28;
+
+// Lengths are stored as u32 in the record header
+pub const MAX_KEY_LEN: usize = u32::MAX as usize;
+pub const MAX_VALUE_LEN: usize = u32::MAX as usize;
+
+// How much of a damaged tail `load` reads at a time looking for intact records
+const TAIL_SCAN_BUF_LEN: usize = 64 * 1024;
+
+// Keys starting with this prefix are reserved for records the store writes
+// for itself, such as the chunks of large values
+const INTERNAL_PREFIX: &ByteStr = b"\xffakv\x00";
+
+pub(crate) fn internal_key(kind: &ByteStr, key: &ByteStr) -> ByteString {
+    let mut internal = ByteString::with_capacity(INTERNAL_PREFIX.len() + kind.len() + 1 + key.len());
+    internal.extend_from_slice(INTERNAL_PREFIX);
+    internal.extend_from_slice(kind);
+    internal.push(0);
+    internal.extend_from_slice(key);
+    internal
+}
+
+/// Splits an internal key back into its kind and the user key it belongs to.
+pub(crate) fn parse_internal_key(key: &ByteStr) -> Option<(&ByteStr, &ByteStr)> {
+    let rest = key.strip_prefix(INTERNAL_PREFIX)?;
+    let split = rest.iter().position(|&b| b == 0)?;
+    Some((&rest[..split], &rest[split + 1..]))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KeyValuePair {
     pub key: ByteString,
     pub value: ByteString,
 }
 
+/// Settings used when opening a store.
+#[derive(Debug, Clone, Default)]
+pub struct StoreOptions {
+    /// Checksum for a newly created store. Existing stores keep the checksum
+    /// they were created with.
+    pub checksum: Checksum,
+    /// Keeps up to this many bytes of recently read keys and values in
+    /// memory, so that reading them again doesn't touch the disk. Reads
+    /// through `AsyncActionKV` share the store and don't use the cache.
+    pub cache_bytes: Option<usize>,
+}
+
 #[derive(Debug)]
 pub struct ActionKV {
     file: File,
-    pub index: HashMap<ByteString, u64>
+    path: PathBuf,
+    read_only: bool,
+    // Where the first record starts, after the file header
+    data_start: u64,
+    checksum: Checksum,
+    pub index: HashMap<ByteString, u64>,
+    column_families: HashMap<String, CfIndex>,
+    bloom: Option<BloomFilter>,
+    // Positions of the merge operands written since each key's value was set
+    merges: HashMap<ByteString, Vec<u64>>,
+    merge_operators: HashMap<String, Box<dyn MergeOperator>>,
+    cache: Option<ValueCache>,
+    metrics: Metrics,
 }
 
 impl ActionKV {
+    /// Opens a store for reading and writing, creating it if needed. Holds an
+    /// exclusive lock on the file until the store is dropped.
+    ///
+    /// Stores written in an older format version are migrated to the current
+    /// one before they are opened.
     pub fn open(file_path: &Path) -> io::Result<Self> {
-        let file = OpenOptions::new()
+        ActionKV::open_with(file_path, &StoreOptions::default())
+    }
+
+    /// Like `open`, with the settings in `options`.
+    pub fn open_with(file_path: &Path, options: &StoreOptions) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
             .read(true)
-            .write(true)
             .create(true)
             .append(true)
             .open(file_path)?;
+        ActionKV::lock(&file, file_path, false)?;
+
+        let checksum = match migrate::read_version(&mut file)? {
+            None => {
+                migrate::write_header(&mut file, migrate::FORMAT_VERSION, options.checksum)?;
+                options.checksum
+            },
+            Some(migrate::FORMAT_VERSION) => migrate::read_checksum(&mut file, migrate::FORMAT_VERSION)?,
+            Some(version) if version < migrate::FORMAT_VERSION => {
+                file = migrate::upgrade_file(file, file_path, version)?;
+                migrate::read_checksum(&mut file, migrate::FORMAT_VERSION)?
+            },
+            Some(version) => return Err(migrate::unsupported_version(file_path, version)),
+        };
+
+        let index = HashMap::new();
+        Ok(ActionKV {
+            file,
+            path: file_path.to_path_buf(),
+            read_only: false,
+            data_start: migrate::FILE_HEADER_LEN,
+            checksum,
+            index,
+            column_families: HashMap::new(),
+            bloom: None,
+            merges: HashMap::new(),
+            merge_operators: merge::builtin_operators(),
+            cache: options.cache_bytes.map(ValueCache::new),
+            metrics: Metrics::default(),
+        })
+    }
+
+    /// Opens an existing store for reading only. Holds a shared lock, so any
+    /// number of readers can open the store while no writer has it open.
+    ///
+    /// Stores in an older format version have to be migrated by opening them
+    /// for writing first.
+    pub fn open_read_only(file_path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(file_path)?;
+        ActionKV::lock(&file, file_path, true)?;
+        ActionKV::from_read_only_file(file, file_path)
+    }
+
+    /// Like `open_read_only`, without taking the lock. A writer may be
+    /// appending to the store meanwhile, so only the whole records up to a
+    /// length read once can be relied on.
+    pub(crate) fn open_unlocked(file_path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(file_path)?;
+        ActionKV::from_read_only_file(file, file_path)
+    }
+
+    fn from_read_only_file(mut file: File, file_path: &Path) -> io::Result<Self> {
+
+        // An empty file has no header to read the checksum from, and no
+        // records to check with it
+        let (data_start, checksum) = match migrate::read_version(&mut file)? {
+            None => (0, Checksum::default()),
+            Some(migrate::FORMAT_VERSION) => (migrate::FILE_HEADER_LEN, migrate::read_checksum(&mut file, migrate::FORMAT_VERSION)?),
+            Some(version) if version < migrate::FORMAT_VERSION => return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} uses format version {} and has to be migrated to version {} before it can be opened read-only",
+                    file_path.display(), version, migrate::FORMAT_VERSION
+                )
+            )),
+            Some(version) => return Err(migrate::unsupported_version(file_path, version)),
+        };
+
         let index = HashMap::new();
-        Ok(ActionKV { file, index })
+        Ok(ActionKV {
+            file,
+            path: file_path.to_path_buf(),
+            read_only: true,
+            data_start,
+            checksum,
+            index,
+            column_families: HashMap::new(),
+            bloom: None,
+            merges: HashMap::new(),
+            merge_operators: merge::builtin_operators(),
+            cache: None,
+            metrics: Metrics::default(),
+        })
     }
 
+    fn lock(file: &File, file_path: &Path, shared: bool) -> io::Result<()> {
+        let locked = if shared { file.try_lock_shared() } else { file.try_lock() };
+        match locked {
+            Ok(()) => Ok(()),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is locked by another process", file_path.display())
+            )),
+            Err(TryLockError::Error(err)) => Err(err),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub(crate) fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} was opened read-only", self.path.display())
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads every record into the index.
+    ///
+    /// A record that was only partly written when the process or machine
+    /// died is dropped, along with anything after it, and the file is cut
+    /// back to the last whole record so that new records don't end up
+    /// behind the damage. Damage followed by intact records isn't a torn
+    /// write, and is reported as corruption.
+    ///
+    /// The dropped bytes are counted in `Metrics::dropped_tail_bytes`, so a
+    /// bit flip in the last record doesn't go unnoticed.
     pub fn load(&mut self) -> io::Result<()> {
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+        let end = self.file.metadata()?.len();
         let mut f = BufReader::new(&mut self.file);
-        loop {
-             let position = f.seek(SeekFrom::Current(0))?;
-            let maybe_kv = ActionKV::process_record(&mut f);
+        f.seek(SeekFrom::Start(self.data_start))?;
+        let (position, err) = loop {
+            let position = f.stream_position()?;
+            let maybe_kv = self.metrics.check(ActionKV::process_record(&mut f, self.checksum, position, end));
 
             let kv = match maybe_kv {
                 Ok(kv) => kv,
                 Err(err) => {
                     match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            break;
+                        io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => {
+                            break (position, err);
                         }
                         _ => return Err(err)
                     }
                 }
             };
+            // Chunks are reached through their manifest, not the index
+            if let Some((large::CHUNK_KIND, _)) = parse_internal_key(&kv.key) {
+                continue;
+            }
+            if cf::index_record(&mut self.column_families, &kv, position) {
+                continue;
+            }
+            let user_key = bloom::bloom_key(&kv.key);
+            if let Some(bloom) = &mut self.bloom {
+                bloom.insert(user_key);
+            }
+            if let Some((merge::MERGE_KIND, _)) = parse_internal_key(&kv.key) {
+                self.merges.entry(user_key.to_vec()).or_default().push(position);
+                continue;
+            }
+            self.merges.remove(user_key);
             self.index.insert(kv.key, position);
+        };
+
+        if position >= end {
+            return Ok(());
+        }
+        if !self.is_torn_tail(position, end)? {
+            return Err(err);
+        }
+        self.metrics.dropped_tail_bytes.fetch_add(end - position, Ordering::Relaxed);
+        if !self.read_only {
+            self.file.set_len(position)?;
         }
 
         Ok(())
     }
 
-    fn process_record<R: Read>(record: &mut R) -> io::Result<KeyValuePair> {
-        let saved_checksum = record.read_u32::<LittleEndian>()?;
-        let key_len = record.read_u32::<LittleEndian>()?;
-        let value_len = record.read_u32::<LittleEndian>()?;
-        let data_len = key_len + value_len;
+    /// Whether the damaged record at `position` is the last thing in the
+    /// file, which is what a torn write leaves behind. Any intact record
+    /// after it means the file was damaged some other way.
+    fn is_torn_tail(&mut self, position: u64, end: u64) -> io::Result<bool> {
+        // An intact header tells us where the next record would start, and
+        // saves looking for records inside this one's data
+        self.file.seek(SeekFrom::Start(position))?;
+        let mut start = match RecordHeader::read(&mut self.file, position) {
+            Ok(header) => {
+                let record_len = RECORD_HEADER_LEN + header.key_len as u64 + header.value_len as u64;
+                if record_len >= end - position {
+                    return Ok(true);
+                }
+                position + record_len
+            },
+            Err(_) => position + 1,
+        };
+
+        // The tail can be most of a big file, so it is scanned a window at a
+        // time. Windows overlap so that every header is whole in one of them.
+        let mut window = vec![0; TAIL_SCAN_BUF_LEN];
+        while start < end {
+            let len = cmp::min(window.len() as u64, end - start) as usize;
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file.read_exact(&mut window[..len])?;
+            let scanned = if start + len as u64 == end { len } else { len - RECORD_HEADER_LEN as usize + 1 };
+            for offset in 0..scanned {
+                if self.is_intact_record(start + offset as u64, &window[offset..len], end)? {
+                    return Ok(false);
+                }
+            }
+            start += scanned as u64;
+        }
+        Ok(true)
+    }
+
+    /// Whether an intact record starts at `position`, where `bytes` were read
+    /// from. Records running past `bytes` are checked against the file.
+    fn is_intact_record(&mut self, position: u64, mut bytes: &[u8], end: u64) -> io::Result<bool> {
+        let header = match RecordHeader::read(&mut bytes, position) {
+            Ok(header) => header,
+            Err(_) => return Ok(false),
+        };
+        let data_len = header.key_len as u64 + header.value_len as u64;
+        if data_len > end - position - RECORD_HEADER_LEN {
+            return Ok(false);
+        }
+        if data_len <= bytes.len() as u64 {
+            let (key, rest) = bytes.split_at(header.key_len as usize);
+            return Ok(self.checksum.record(key, &rest[..header.value_len as usize]) == header.checksum);
+        }
+
+        let mut hasher = self.checksum.hasher();
+        hasher.lengths(header.key_len, header.value_len);
+        self.file.seek(SeekFrom::Start(position + RECORD_HEADER_LEN))?;
+        let mut data = Read::by_ref(&mut self.file).take(data_len);
+        let mut buf = vec![0; TAIL_SCAN_BUF_LEN];
+        loop {
+            let n = data.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.write(&buf[..n]);
+        }
+        Ok(hasher.finish() == header.checksum)
+    }
+
+    fn process_record<R: Read>(record: &mut R, checksum: Checksum, position: u64, end: u64) -> io::Result<KeyValuePair> {
+        let RecordHeader { checksum: saved_checksum, key_len, value_len, .. } = RecordHeader::read(record, position)?;
+        // Summed as u64 so that two large lengths can't wrap around
+        let data_len = key_len as u64 + value_len as u64;
+
+        // The lengths have been checked, so a record running past the end of
+        // the file is the tail of an interrupted write
+        let remaining = end.saturating_sub(position + RECORD_HEADER_LEN);
+        if data_len > remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "record at {} claims {} bytes of data but only {} remain",
+                    position, data_len, remaining
+                )
+            ));
+        }
 
         let mut data = ByteString::with_capacity(data_len as usize);
 
         {
             record.by_ref()
-                .take(data_len as u64)
+                .take(data_len)
                 .read_to_end(&mut data)?;
         }
 
-        debug_assert_eq!(data.len(), data_len as usize);
-
-        let checksum = crc::crc32::checksum_ieee(&data);
-        if checksum != saved_checksum {
-            panic!(
-                "Data corruption encountered ({:08x} != {:08x})",
-                checksum,
-                saved_checksum
-            );
+        if data.len() as u64 != data_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
         let value = data.split_off(key_len as usize);
         let key = data;
+
+        let computed = checksum.record(&key, &value);
+        if computed != saved_checksum {
+            return Err(checksum::corruption(position, computed, saved_checksum));
+        }
+
         Ok( KeyValuePair { key, value })
     }
     pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
-       let position = match self.index.get(key) {
-           None => return Ok(None),
-           Some(position) => *position
-       };
+        let started = Instant::now();
+        let value = self.get_merged(key);
+        self.metrics.gets.fetch_add(1, Ordering::Relaxed);
+        self.metrics.get_latency.observe(started.elapsed());
+        value
+    }
+
+    fn get_merged(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        if let Some(value) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+            return Ok(Some(value));
+        }
+        let base = self.get_base(key)?;
+        let operands = self.merge_operands(key)?;
+        let value = self.apply_merges(key, base, &operands)?;
+        if let (Some(cache), Some(value)) = (&mut self.cache, &value) {
+            cache.insert(key, value);
+        }
+        Ok(value)
+    }
+
+    /// The value of `key` without any merges applied.
+    fn get_base(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let position = match self.locate(key) {
+            None => return Ok(None),
+            Some(Location::Record(position)) => position,
+            Some(Location::Large(_)) => {
+                let mut value = ByteString::new();
+                self.get_large_base(key)?.unwrap().read_to_end(&mut value)?;
+                return Ok(Some(value));
+            }
+        };
 
         let kv = self.get_at(position)?;
         Ok(Some(kv.value))
     }
 
+    /// Finds the record holding the current value of `key`. A key has a
+    /// chunked value when its manifest was written after its last plain
+    /// record.
+    pub(crate) fn locate(&self, key: &ByteStr) -> Option<Location> {
+        let record = self.index.get(key).copied();
+        let large = self.index.get(&internal_key(large::MANIFEST_KIND, key)).copied();
+        match (record, large) {
+            (None, None) => None,
+            (Some(record), Some(large)) if large > record => Some(Location::Large(large)),
+            (None, Some(large)) => Some(Location::Large(large)),
+            (Some(record), _) => Some(Location::Record(record)),
+        }
+    }
+
     pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
+        let end = self.file.metadata()?.len();
         let mut file = BufReader::new(&mut self.file);
         file.seek(SeekFrom::Start(position))?;
-        let kv = ActionKV::process_record(&mut file)?;
+        let kv = self.metrics.check(ActionKV::process_record(&mut file, self.checksum, position, end))?;
 
         Ok(kv)
     }
 
     pub fn find(&mut self, target: &ByteStr) -> io::Result<Option<(u64, ByteString)>> {
+        if !self.may_contain(target) {
+            return Ok(None);
+        }
+        let end = self.file.metadata()?.len();
         let mut file = BufReader::new(&mut self.file);
+        file.seek(SeekFrom::Start(self.data_start))?;
         let mut found: Option<(u64, ByteString)> = None;
 
         loop {
-            let position = file.seek(SeekFrom::Current(0))?;
-            let maybe_kv = ActionKV::process_record(&mut file);
+            let position = file.stream_position()?;
+            let maybe_kv = self.metrics.check(ActionKV::process_record(&mut file, self.checksum, position, end));
             let kv =  match maybe_kv {
                 Ok(kv) => kv,
                 Err(err) => {
@@ -138,17 +527,68 @@ impl ActionKV {
         self.insert(key, b"")
     }
     pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        ActionKV::check_user_key(key)?;
+        let started = Instant::now();
         let position = self.insert_but_ignore_index(key, value)?;
-        self.index.insert(key.to_vec(), position);
+        self.index_key(key.to_vec(), position);
+        self.metrics.record_write(value.len() as u64, started);
 
         Ok(())
     }
 
-    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
-        let mut file = BufWriter::new(&mut self.file);
+    /// Points the index at the record for `key`.
+    pub(crate) fn index_key(&mut self, key: ByteString, position: u64) {
+        let user_key = bloom::bloom_key(&key);
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(user_key);
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.remove(user_key);
+        }
+        // Merges before a new value no longer apply
+        self.merges.remove(user_key);
+        self.index.insert(key, position);
+    }
 
+    pub(crate) fn check_user_key(key: &ByteStr) -> io::Result<()> {
+        if key.starts_with(INTERNAL_PREFIX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keys starting with \\xffakv\\x00 are reserved for internal use"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Keys are stored with a 32-bit length, which longer keys would overflow.
+    pub(crate) fn check_key_len(key: &ByteStr) -> io::Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key is {} bytes, the limit is {} bytes", key.len(), MAX_KEY_LEN)
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_value_len(val_len: usize) -> io::Result<()> {
+        if val_len > MAX_VALUE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("value is {} bytes, the limit is {} bytes; use insert_large for bigger values", val_len, MAX_VALUE_LEN)
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
+        self.check_writable()?;
         let key_len = key.len();
         let val_len = value.len();
+        ActionKV::check_key_len(key)?;
+        ActionKV::check_value_len(val_len)?;
+
+        let mut file = BufWriter::new(&mut self.file);
         let mut tmp = ByteString::with_capacity(key_len + val_len);
 
         for byte in key {
@@ -159,15 +599,20 @@ impl ActionKV {
             tmp.push(*byte);
         }
 
-        let checksum = crc32::checksum_ieee(&tmp);
+        let checksum = self.checksum.record(key, value);
 
-        let next_byte = SeekFrom::End(0);
-        let current_position = file.seek(SeekFrom::Current(0))?;
-        file.seek(next_byte)?;
-        file.write_u32::<LittleEndian>(checksum)?;
-        file.write_u32::<LittleEndian>(key_len as u32)?;
-        file.write_u32::<LittleEndian>(val_len as u32)?;
+        // Reads can leave the cursor anywhere in the file, so the record's
+        // position has to come from the end of the file rather than the cursor
+        let current_position = file.seek(SeekFrom::End(0))?;
+        let timestamp = checksum::now_millis();
+        RecordHeader { checksum, key_len: key_len as u32, value_len: val_len as u32, timestamp }.write(&mut file)?;
         file.write_all(&tmp)?;
+        let record_len = RECORD_HEADER_LEN + tmp.len() as u64;
+        self.metrics.bytes_written.fetch_add(record_len, Ordering::Relaxed);
+        // `find` relies on the filter for records the index doesn't know of
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(bloom::bloom_key(key));
+        }
 
         Ok(current_position)
     }
@@ -175,14 +620,150 @@ impl ActionKV {
     pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
         self.insert(key, value)
     }
-}
 
+    /// Flushes the records written so far to disk, so that they survive a
+    /// power failure.
+    pub fn sync(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        self.file.sync_all()?;
+        self.metrics.fsync_latency.observe(started.elapsed());
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the user keys starting with `prefix` in sorted order,
+    /// including deleted ones.
+    fn keys_with_prefix(&self, prefix: &ByteStr) -> Vec<ByteString> {
+        let keys: BTreeSet<&ByteStr> = self.index.keys()
+            .filter_map(|key| match parse_internal_key(key) {
+                None => Some(key.as_slice()),
+                Some((large::MANIFEST_KIND, user_key)) => Some(user_key),
+                Some(_) => None,
+            })
+            .chain(self.merges.keys().map(|key| key.as_slice()))
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.into_iter().map(|key| key.to_vec()).collect()
+    }
+
+    /// Returns the live key/value pairs whose key starts with `prefix`,
+    /// sorted by key. Deleted keys (empty values) are skipped.
+    pub fn scan(&mut self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let mut pairs = Vec::new();
+        for key in self.keys_with_prefix(prefix) {
+            let value = self.get(&key)?.unwrap_or_default();
+            if !value.is_empty() {
+                pairs.push(KeyValuePair { key, value });
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    /// Rewrites the store so that it only holds the latest value of each
+    /// live key, dropping overwritten records and deleted keys.
+    pub fn compact(&mut self) -> io::Result<()> {
+        self.check_writable()?;
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        File::create(&tmp_path)?;
+        let options = StoreOptions { checksum: self.checksum, ..StoreOptions::default() };
+        let mut compacted = ActionKV::open_with(&tmp_path, &options)?;
+        for key in self.keys_with_prefix(b"") {
+            // Merges are collapsed into a plain value
+            if self.merges.contains_key(&key) {
+                let value = self.get(&key)?.unwrap_or_default();
+                if !value.is_empty() {
+                    compacted.insert(&key, &value)?;
+                }
+                continue;
+            }
+            match self.locate(&key) {
+                Some(Location::Record(position)) => {
+                    let kv = self.get_at(position)?;
+                    if !kv.value.is_empty() {
+                        compacted.insert(&kv.key, &kv.value)?;
+                    }
+                },
+                Some(Location::Large(_)) => {
+                    let value = self.get_large(&key)?.unwrap();
+                    if value.remaining() > 0 {
+                        compacted.insert_large(&key, value)?;
+                    }
+                },
+                None => {},
+            }
+        }
+        self.compact_cfs(&mut compacted)?;
+        let started = Instant::now();
+        compacted.file.sync_all()?;
+        self.metrics.fsync_latency.observe(started.elapsed());
+
+        fs::rename(&tmp_path, &self.path)?;
+        compacted.path = self.path.clone();
+        if let Some(bloom) = &self.bloom {
+            compacted.set_bloom_filter(bloom.empty_like());
+        }
+        compacted.merge_operators = std::mem::take(&mut self.merge_operators);
+        let old_len = self.file.metadata()?.len();
+        let new_len = compacted.file.metadata()?.len();
+        // Only the copy's bytes carry over; its inserts weren't the user's
+        self.metrics.bytes_written.fetch_add(compacted.metrics.bytes_written(), Ordering::Relaxed);
+        self.metrics.compactions.fetch_add(1, Ordering::Relaxed);
+        self.metrics.reclaimed_bytes.fetch_add(old_len.saturating_sub(new_len), Ordering::Relaxed);
+        compacted.metrics = std::mem::take(&mut self.metrics);
+        compacted.cache = self.cache.take();
+        if let Some(cache) = &mut compacted.cache {
+            cache.clear();
+        }
+        *self = compacted;
+
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn writers_exclude_other_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locked.akv");
+
+        let writer = ActionKV::open(&path).unwrap();
+        let err = ActionKV::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(ActionKV::open_read_only(&path).is_err());
+        drop(writer);
+
+        let mut reader = ActionKV::open_read_only(&path).unwrap();
+        let _other_reader = ActionKV::open_read_only(&path).unwrap();
+        assert!(ActionKV::open(&path).is_err());
+        let err = reader.insert(b"k", b"v").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn empty_file_opens_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.akv");
+        File::create(&path).unwrap();
+
+        let mut store = ActionKV::open_read_only(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"k").unwrap(), None);
+        assert!(store.scan(b"").unwrap().is_empty());
+    }
 }
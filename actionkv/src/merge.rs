@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::time::Instant;
+use serde_json::{Map, Value};
+use crate::{internal_key, ActionKV, ByteStr, ByteString};
+
+// Records holding one operand of a merge, keyed by the user key. The value
+// is the operator's name, prefixed by its length, followed by the operand.
+pub(crate) const MERGE_KIND: &ByteStr = b"merge";
+
+/// Combines a key's value with the operands merged into it since. Operators
+/// are looked up by name when a value is read, so a store has to have every
+/// operator it was written with registered.
+pub trait MergeOperator: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Applies `operands`, oldest first, to `existing`, which is `None` for
+    /// keys that have no value or were deleted.
+    fn merge(&self, key: &ByteStr, existing: Option<&ByteStr>, operands: &[ByteString]) -> io::Result<ByteString>;
+
+    /// Checks an operand before `ActionKV::merge` writes it, so that an
+    /// operand `merge` would fail on is turned away instead of breaking every
+    /// later read of the key. Accepts any operand by default.
+    fn check_operand(&self, _operand: &ByteStr) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Adds little-endian `u64`s, wrapping on overflow. A missing value counts
+/// as 0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64Add;
+
+impl MergeOperator for U64Add {
+    fn name(&self) -> &str {
+        "u64_add"
+    }
+
+    fn merge(&self, _key: &ByteStr, existing: Option<&ByteStr>, operands: &[ByteString]) -> io::Result<ByteString> {
+        let mut sum = existing.map(decode_u64).transpose()?.unwrap_or(0);
+        for operand in operands {
+            sum = sum.wrapping_add(decode_u64(operand)?);
+        }
+        Ok(sum.to_le_bytes().to_vec())
+    }
+
+    fn check_operand(&self, operand: &ByteStr) -> io::Result<()> {
+        if operand.len() != 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("u64_add needs 8 byte operands, got {} bytes", operand.len())
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn decode_u64(bytes: &ByteStr) -> io::Result<u64> {
+    let bytes: [u8; 8] = bytes.try_into().map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("u64_add needs 8 byte values, got {} bytes", bytes.len())
+    ))?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Appends each operand's bytes to the value.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn merge(&self, _key: &ByteStr, existing: Option<&ByteStr>, operands: &[ByteString]) -> io::Result<ByteString> {
+        let mut value = existing.unwrap_or_default().to_vec();
+        for operand in operands {
+            value.extend_from_slice(operand);
+        }
+        Ok(value)
+    }
+}
+
+/// Applies JSON merge patches (RFC 7386) to a JSON value.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonMergePatch;
+
+impl MergeOperator for JsonMergePatch {
+    fn name(&self) -> &str {
+        "json_merge_patch"
+    }
+
+    fn merge(&self, _key: &ByteStr, existing: Option<&ByteStr>, operands: &[ByteString]) -> io::Result<ByteString> {
+        let mut value = match existing {
+            None => Value::Null,
+            Some(existing) => serde_json::from_slice(existing)?,
+        };
+        for operand in operands {
+            let patch: Value = serde_json::from_slice(operand)?;
+            merge_patch(&mut value, &patch);
+        }
+        Ok(serde_json::to_vec(&value)?)
+    }
+
+    fn check_operand(&self, operand: &ByteStr) -> io::Result<()> {
+        serde_json::from_slice::<Value>(operand)
+            .map(drop)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+}
+
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        },
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(name);
+        } else {
+            merge_patch(target.entry(name.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// The operators every store starts with.
+pub(crate) fn builtin_operators() -> HashMap<String, Box<dyn MergeOperator>> {
+    let operators: [Box<dyn MergeOperator>; 3] = [Box::new(U64Add), Box::new(Append), Box::new(JsonMergePatch)];
+    operators.into_iter().map(|op| (op.name().to_string(), op)).collect()
+}
+
+/// Splits the value of a merge record into the operator's name and the
+/// operand.
+pub(crate) fn decode_operand(value: &ByteStr) -> io::Result<(&str, &ByteStr)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed merge record");
+    let (&name_len, rest) = value.split_first().ok_or_else(invalid)?;
+    if rest.len() < name_len as usize {
+        return Err(invalid());
+    }
+    let (name, operand) = rest.split_at(name_len as usize);
+    Ok((std::str::from_utf8(name).map_err(|_| invalid())?, operand))
+}
+
+impl ActionKV {
+    /// Makes `operator` available to `merge` and to reads, replacing any
+    /// operator of the same name.
+    pub fn register_merge_operator(&mut self, operator: Box<dyn MergeOperator>) {
+        self.merge_operators.insert(operator.name().to_string(), operator);
+    }
+
+    /// Merges `operand` into the value of `key` with the operator called
+    /// `operator`. Only the operand is written, so there's no read to race
+    /// with other writers; reads fold the operands into the value, and
+    /// compaction stores the result.
+    pub fn merge(&mut self, key: &ByteStr, operator: &str, operand: &ByteStr) -> io::Result<()> {
+        ActionKV::check_user_key(key)?;
+        match self.merge_operators.get(operator) {
+            None => return Err(unknown_operator(operator)),
+            Some(merge_operator) => merge_operator.check_operand(operand)?,
+        }
+        if operator.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "merge operator names are limited to 255 bytes"));
+        }
+
+        let started = Instant::now();
+        let mut value = ByteString::with_capacity(1 + operator.len() + operand.len());
+        value.push(operator.len() as u8);
+        value.extend_from_slice(operator.as_bytes());
+        value.extend_from_slice(operand);
+        let position = self.insert_but_ignore_index(&internal_key(MERGE_KIND, key), &value)?;
+
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(key);
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.remove(key);
+        }
+        self.merges.entry(key.to_vec()).or_default().push(position);
+        // Counted as an insert even for empty operands, as the record isn't a delete
+        self.metrics.record_write(value.len() as u64, started);
+        Ok(())
+    }
+
+    /// Reads the operands merged into `key` since its value was last set.
+    pub(crate) fn merge_operands(&mut self, key: &ByteStr) -> io::Result<Vec<ByteString>> {
+        let positions = self.merges.get(key).cloned().unwrap_or_default();
+        let mut operands = Vec::with_capacity(positions.len());
+        for position in positions {
+            operands.push(self.get_at(position)?.value);
+        }
+        Ok(operands)
+    }
+
+    /// Folds the merge records in `operands` into `base`, a run of operands
+    /// for the same operator at a time.
+    pub(crate) fn apply_merges(&self, key: &ByteStr, base: Option<ByteString>, operands: &[ByteString]) -> io::Result<Option<ByteString>> {
+        if operands.is_empty() {
+            return Ok(base);
+        }
+
+        // Deleted keys are stored as empty values
+        let mut value = base.filter(|value| !value.is_empty());
+        let mut run: Vec<ByteString> = Vec::new();
+        for (i, record) in operands.iter().enumerate() {
+            let (operator, operand) = decode_operand(record)?;
+            run.push(operand.to_vec());
+
+            let run_ends = match operands.get(i + 1) {
+                None => true,
+                Some(next) => decode_operand(next)?.0 != operator,
+            };
+            if run_ends {
+                let op = self.merge_operators.get(operator).ok_or_else(|| unknown_operator(operator))?;
+                value = Some(op.merge(key, value.as_deref(), &run)?);
+                run.clear();
+            }
+        }
+        Ok(value)
+    }
+}
+
+fn unknown_operator(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("no merge operator called {:?} is registered", name)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_fold_and_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("merge.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+
+        store.merge(b"count", "u64_add", &5u64.to_le_bytes()).unwrap();
+        store.merge(b"count", "u64_add", &7u64.to_le_bytes()).unwrap();
+        store.insert(b"log", b"a").unwrap();
+        store.merge(b"log", "append", b"b").unwrap();
+        store.merge(b"log", "append", b"c").unwrap();
+        store.insert(b"doc", br#"{"a":1,"b":{"c":2}}"#).unwrap();
+        store.merge(b"doc", "json_merge_patch", br#"{"a":null,"b":{"d":3}}"#).unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"count").unwrap(), Some(12u64.to_le_bytes().to_vec()));
+        assert_eq!(store.get(b"log").unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(store.get(b"doc").unwrap(), Some(br#"{"b":{"c":2,"d":3}}"#.to_vec()));
+        assert_eq!(store.scan(b"").unwrap().len(), 3);
+
+        // Setting a value drops the operands before it
+        store.insert(b"log", b"x").unwrap();
+        store.merge(b"log", "append", b"y").unwrap();
+        assert_eq!(store.get(b"log").unwrap(), Some(b"xy".to_vec()));
+
+        store.compact().unwrap();
+        assert_eq!(store.stats().unwrap().total_records, 3);
+        assert_eq!(store.get(b"count").unwrap(), Some(12u64.to_le_bytes().to_vec()));
+        store.merge(b"count", "u64_add", &1u64.to_le_bytes()).unwrap();
+        assert_eq!(store.get(b"count").unwrap(), Some(13u64.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn unknown_and_bad_operands_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("bad.akv")).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.merge(b"k", "nope", b"").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        assert_eq!(store.merge(b"k", "u64_add", b"short").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.merge(b"k", "json_merge_patch", b"{not json").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.get(b"k").unwrap(), None);
+        assert_eq!(store.metrics().inserts(), 0);
+
+        // A value of the wrong shape still fails, when it is read
+        store.insert(b"k", b"short").unwrap();
+        store.merge(b"k", "u64_add", &1u64.to_le_bytes()).unwrap();
+        assert_eq!(store.get(b"k").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(store.metrics().inserts(), 2);
+    }
+}
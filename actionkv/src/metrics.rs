@@ -0,0 +1,212 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+/// Counts durations into fixed buckets, the way Prometheus histograms do.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    // One more than the bounds, for durations above the last one
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// Operational counters for a store, updated as it is used. Counters only
+/// go up, and carry over when the store is compacted.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub(crate) gets: AtomicU64,
+    pub(crate) inserts: AtomicU64,
+    pub(crate) deletes: AtomicU64,
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) checksum_failures: AtomicU64,
+    pub(crate) compactions: AtomicU64,
+    pub(crate) reclaimed_bytes: AtomicU64,
+    pub(crate) dropped_tail_bytes: AtomicU64,
+    pub(crate) get_latency: LatencyHistogram,
+    pub(crate) insert_latency: LatencyHistogram,
+    pub(crate) fsync_latency: LatencyHistogram,
+}
+
+impl Metrics {
+    pub fn gets(&self) -> u64 {
+        self.gets.load(Ordering::Relaxed)
+    }
+
+    /// Writes of non-empty values, through any of the insert methods, and
+    /// merges
+    pub fn inserts(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed)
+    }
+
+    pub fn deletes(&self) -> u64 {
+        self.deletes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of records appended to the file, headers included
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Records that failed their checksum when read
+    pub fn checksum_failures(&self) -> u64 {
+        self.checksum_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    /// Bytes compaction has removed from the file
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.reclaimed_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of damaged records that `load` dropped from the end of the file
+    pub fn dropped_tail_bytes(&self) -> u64 {
+        self.dropped_tail_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn get_latency(&self) -> &LatencyHistogram {
+        &self.get_latency
+    }
+
+    pub fn insert_latency(&self) -> &LatencyHistogram {
+        &self.insert_latency
+    }
+
+    pub fn fsync_latency(&self) -> &LatencyHistogram {
+        &self.fsync_latency
+    }
+
+    /// Counts a write that started at `started`. Empty values are deletes.
+    pub(crate) fn record_write(&self, value_len: u64, started: Instant) {
+        let counter = if value_len == 0 { &self.deletes } else { &self.inserts };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.insert_latency.observe(started.elapsed());
+    }
+
+    /// Counts `result` as a checksum failure if it is one.
+    pub(crate) fn check<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(err) = &result {
+            if crate::checksum::is_corruption(err) {
+                self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("akv_gets_total", "Reads of a key's value.", self.gets()),
+            ("akv_inserts_total", "Writes of a non-empty value.", self.inserts()),
+            ("akv_deletes_total", "Deletes.", self.deletes()),
+            ("akv_written_bytes_total", "Bytes of records appended to the store file.", self.bytes_written()),
+            ("akv_checksum_failures_total", "Records that failed their checksum when read.", self.checksum_failures()),
+            ("akv_compactions_total", "Compactions run.", self.compactions()),
+            ("akv_compaction_reclaimed_bytes_total", "Bytes removed from the store file by compaction.", self.reclaimed_bytes()),
+            ("akv_dropped_tail_bytes_total", "Bytes of damaged records dropped from the end of the store file on load.", self.dropped_tail_bytes()),
+        ];
+        for (name, help, value) in counters {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+
+        let histograms = [
+            ("akv_get_duration_seconds", "Time taken by gets.", &self.get_latency),
+            ("akv_insert_duration_seconds", "Time taken by inserts and deletes.", &self.insert_latency),
+            ("akv_fsync_duration_seconds", "Time taken to flush the store file to disk.", &self.fsync_latency),
+        ];
+        for (name, help, histogram) in histograms {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            // Prometheus buckets count everything up to their bound
+            let mut cumulative = 0;
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+            }
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count()).unwrap();
+            writeln!(out, "{}_sum {}", name, histogram.sum().as_secs_f64()).unwrap();
+            writeln!(out, "{}_count {}", name, histogram.count()).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ActionKV;
+
+    #[test]
+    fn store_operations_are_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("metrics.akv")).unwrap();
+        store.load().unwrap();
+        store.insert(b"k", b"v").unwrap();
+        store.insert(b"gone", b"v").unwrap();
+        store.delete(b"gone").unwrap();
+        store.get(b"k").unwrap();
+        store.sync().unwrap();
+        store.compact().unwrap();
+
+        let metrics = store.metrics();
+        assert_eq!((metrics.gets(), metrics.inserts(), metrics.deletes()), (1, 2, 1));
+        // Three records, then one more when compaction copies "k"
+        assert_eq!(metrics.bytes_written(), 3 * 28 + 11 + 28 + 2);
+        assert_eq!((metrics.compactions(), metrics.reclaimed_bytes()), (1, 2 * 28 + 5 + 4));
+        assert_eq!(metrics.fsync_latency().count(), 2);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE akv_gets_total counter\nakv_gets_total 1\n"), "{}", text);
+        assert!(text.contains("akv_fsync_duration_seconds_bucket{le=\"+Inf\"} 2\n"), "{}", text);
+        assert!(text.contains("akv_fsync_duration_seconds_count 2\n"), "{}", text);
+    }
+
+    #[test]
+    fn checksum_failures_are_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corrupt.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"k", b"value").unwrap();
+        drop(store);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut store = ActionKV::open_read_only(&path).unwrap();
+        store.load().unwrap();
+        assert!(store.get_at(crate::migrate::FILE_HEADER_LEN).is_err());
+        assert!(store.metrics().checksum_failures() >= 1);
+    }
+}
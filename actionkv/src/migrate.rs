@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use crate::checksum::RecordHeader;
+use crate::{large, parse_internal_key, ActionKV, ByteString, Checksum, RECORD_HEADER_LEN};
+
+/// Every store written since format version 1 starts with these bytes.
+pub const FILE_MAGIC: &[u8; 8] = b"ACTIONKV";
+
+/// The format version written by this build.
+///
+/// - 0: no file header, records are a CRC-32 of the key and value followed
+///   by the key and value lengths
+/// - 1: adds the file header
+/// - 2: adds the checksum algorithm to the file header; record checksums are
+///   64 bits wide, cover the lengths too and the lengths get a CRC-32C
+/// - 3: records carry the time they were written
+pub const FORMAT_VERSION: u32 = 3;
+
+// magic, version and checksum algorithm
+pub const FILE_HEADER_LEN: u64 = 16;
+
+// Version 1 headers had no checksum algorithm
+const V1_FILE_HEADER_LEN: u64 = 12;
+
+/// A record decoded from any format version.
+struct Record {
+    key: ByteString,
+    value: ByteString,
+    timestamp: u64,
+}
+
+fn record_header_len(version: u32) -> u64 {
+    match version {
+        0 | 1 => 12,
+        2 => 20,
+        _ => RECORD_HEADER_LEN,
+    }
+}
+
+fn migration_corruption(version: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Data corruption encountered while migrating from version {}", version)
+    )
+}
+
+/// Reads the next record of a store in the given version. Returns `None` at
+/// the end of the data, including when the last record was only partly
+/// written.
+fn read_record(version: u32, checksum: Checksum, src: &mut dyn Read) -> io::Result<Option<Record>> {
+    let mut header = vec![0; record_header_len(version) as usize];
+    match src.read_exact(&mut header) {
+        Ok(()) => {},
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut header = &header[..];
+
+    let (saved_checksum, key_len, value_len, timestamp) = match version {
+        0 | 1 => {
+            let saved = header.read_u32::<LittleEndian>()? as u64;
+            (saved, header.read_u32::<LittleEndian>()?, header.read_u32::<LittleEndian>()?, 0)
+        },
+        2 => {
+            let saved = header.read_u64::<LittleEndian>()?;
+            let key_len = header.read_u32::<LittleEndian>()?;
+            let value_len = header.read_u32::<LittleEndian>()?;
+            let mut lengths = [0; 8];
+            lengths[..4].copy_from_slice(&key_len.to_le_bytes());
+            lengths[4..].copy_from_slice(&value_len.to_le_bytes());
+            if crc32::checksum_castagnoli(&lengths) != header.read_u32::<LittleEndian>()? {
+                return Err(migration_corruption(version));
+            }
+            (saved, key_len, value_len, 0)
+        },
+        _ => {
+            let header = RecordHeader::read(&mut header, 0)?;
+            (header.checksum, header.key_len, header.value_len, header.timestamp)
+        },
+    };
+
+    let data_len = key_len as u64 + value_len as u64;
+    let mut data = ByteString::new();
+    src.take(data_len).read_to_end(&mut data)?;
+    if data.len() as u64 != data_len {
+        return Ok(None);
+    }
+
+    let value = data.split_off(key_len as usize);
+    let key = data;
+    let computed = match version {
+        0 | 1 => {
+            let mut crc = crc32::update(0, &crc32::IEEE_TABLE, &key);
+            crc = crc32::update(crc, &crc32::IEEE_TABLE, &value);
+            crc as u64
+        },
+        _ => checksum.record(&key, &value),
+    };
+    if computed != saved_checksum {
+        return Err(migration_corruption(version));
+    }
+
+    Ok(Some(Record { key, value, timestamp }))
+}
+
+/// Writes a record in the given version. Only the versions that a migration
+/// can produce are supported.
+fn write_record(version: u32, checksum: Checksum, dst: &mut dyn Write, record: &Record) -> io::Result<()> {
+    let key_len = record.key.len() as u32;
+    let value_len = record.value.len() as u32;
+    match version {
+        1 => {
+            let mut crc = crc32::update(0, &crc32::IEEE_TABLE, &record.key);
+            crc = crc32::update(crc, &crc32::IEEE_TABLE, &record.value);
+            dst.write_u32::<LittleEndian>(crc)?;
+            dst.write_u32::<LittleEndian>(key_len)?;
+            dst.write_u32::<LittleEndian>(value_len)?;
+        },
+        2 => {
+            let mut lengths = [0; 8];
+            lengths[..4].copy_from_slice(&key_len.to_le_bytes());
+            lengths[4..].copy_from_slice(&value_len.to_le_bytes());
+            dst.write_u64::<LittleEndian>(checksum.record(&record.key, &record.value))?;
+            dst.write_u32::<LittleEndian>(key_len)?;
+            dst.write_u32::<LittleEndian>(value_len)?;
+            dst.write_u32::<LittleEndian>(crc32::checksum_castagnoli(&lengths))?;
+        },
+        _ => {
+            let checksum = checksum.record(&record.key, &record.value);
+            RecordHeader { checksum, key_len, value_len, timestamp: record.timestamp }.write(dst)?;
+        },
+    }
+    dst.write_all(&record.key)?;
+    dst.write_all(&record.value)
+}
+
+/// Rewrites the records of a version `version` store as version
+/// `version + 1`.
+///
+/// Records change size between versions, which moves them around. Large
+/// values refer to their chunks by position, so a first pass works out where
+/// every chunk ends up and the second pass rewrites the references as it
+/// copies the records.
+fn upgrade_records(src: &mut File, dst: &mut dyn Write, version: u32, checksum: Checksum) -> io::Result<()> {
+    let mut src = BufReader::new(src);
+    let mut moved = HashMap::new();
+
+    src.seek(SeekFrom::Start(data_start(version)))?;
+    let mut old_position = data_start(version);
+    let mut new_position = data_start(version + 1);
+    while let Some(record) = read_record(version, checksum, &mut src)? {
+        if let Some((large::CHUNK_KIND, _)) = parse_internal_key(&record.key) {
+            moved.insert(old_position, new_position);
+        }
+        let data_len = (record.key.len() + record.value.len()) as u64;
+        old_position += record_header_len(version) + data_len;
+        new_position += record_header_len(version + 1) + data_len;
+    }
+
+    src.seek(SeekFrom::Start(data_start(version)))?;
+    while let Some(mut record) = read_record(version, checksum, &mut src)? {
+        large::remap_positions(&record.key, &mut record.value, &moved)?;
+        write_record(version + 1, checksum, dst, &record)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the format version of a store. Returns `None` for an empty file
+/// and 0 for files written before stores had a header.
+pub fn read_version(file: &mut File) -> io::Result<Option<u32>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = Vec::with_capacity(V1_FILE_HEADER_LEN as usize);
+    Read::by_ref(file).take(V1_FILE_HEADER_LEN).read_to_end(&mut header)?;
+
+    if header.is_empty() {
+        return Ok(None);
+    }
+    if header.len() == V1_FILE_HEADER_LEN as usize && header.starts_with(FILE_MAGIC) {
+        let version = (&header[FILE_MAGIC.len()..]).read_u32::<LittleEndian>()?;
+        return Ok(Some(version));
+    }
+    Ok(Some(0))
+}
+
+pub(crate) fn write_header<W: Write>(file: &mut W, version: u32, checksum: Checksum) -> io::Result<()> {
+    file.write_all(FILE_MAGIC)?;
+    file.write_u32::<LittleEndian>(version)?;
+    if version >= 2 {
+        file.write_u32::<LittleEndian>(checksum.code())?;
+    }
+    Ok(())
+}
+
+/// Reads which checksum algorithm a store's records use.
+pub(crate) fn read_checksum(file: &mut File, version: u32) -> io::Result<Checksum> {
+    if version < 2 {
+        return Ok(Checksum::Crc32);
+    }
+    file.seek(SeekFrom::Start(V1_FILE_HEADER_LEN))?;
+    Checksum::from_code(file.read_u32::<LittleEndian>()?)
+}
+
+/// Where the records of a store with the given version start.
+pub(crate) fn data_start(version: u32) -> u64 {
+    match version {
+        0 => 0,
+        1 => V1_FILE_HEADER_LEN,
+        _ => FILE_HEADER_LEN,
+    }
+}
+
+pub(crate) fn unsupported_version(path: &Path, version: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} uses format version {}, but this build only understands versions up to {}",
+            path.display(), version, FORMAT_VERSION
+        )
+    )
+}
+
+/// Rewrites the store at `path` one version at a time until it reaches
+/// `FORMAT_VERSION`, and returns a handle to the rewritten file.
+///
+/// Each step writes to a temporary file that is locked before it is renamed
+/// over the store, so no other process can open the store in between.
+pub(crate) fn upgrade_file(mut file: File, path: &Path, from: u32) -> io::Result<File> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".migrate");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    for version in from..FORMAT_VERSION {
+        // Stores from before version 2 use CRC-32, which is also the default
+        let checksum = read_checksum(&mut file, version)?;
+        let mut upgraded = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        ActionKV::lock(&upgraded, &tmp_path, false)?;
+
+        {
+            let mut dst = BufWriter::new(&mut upgraded);
+            write_header(&mut dst, version + 1, checksum)?;
+            upgrade_records(&mut file, &mut dst, version, checksum)?;
+            dst.flush()?;
+        }
+        upgraded.sync_all()?;
+
+        fs::rename(&tmp_path, path)?;
+        file = upgraded;
+    }
+
+    Ok(file)
+}
+
+/// Migrates the store at `path` to the current format version. Returns the
+/// version it was at and the version it is at now.
+pub fn upgrade(path: &Path) -> io::Result<(u32, u32)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    ActionKV::lock(&file, path, false)?;
+
+    match read_version(&mut file)? {
+        None => Ok((FORMAT_VERSION, FORMAT_VERSION)),
+        Some(version) if version > FORMAT_VERSION => Err(unsupported_version(path, version)),
+        Some(version) => {
+            upgrade_file(file, path, version)?;
+            Ok((version, FORMAT_VERSION))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crc::crc32;
+
+    fn v0_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut data = key.to_vec();
+        data.extend_from_slice(value);
+        let mut record = Vec::new();
+        record.write_u32::<LittleEndian>(crc32::checksum_ieee(&data)).unwrap();
+        record.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+        record.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+        record.extend_from_slice(&data);
+        record
+    }
+
+    #[test]
+    fn large_values_survive_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v0-large.akv");
+        let chunk_key = crate::internal_key(large::CHUNK_KIND, b"big");
+        let chunk_record_len = 12 + chunk_key.len() as u64 + 8 + 3;
+
+        // Two chunks holding "abc" and "def", and the manifest pointing at them
+        let mut v0 = v0_record(b"a", b"1");
+        let first_chunk = v0.len() as u64;
+        let mut chunk = (first_chunk + chunk_record_len).to_le_bytes().to_vec();
+        chunk.extend_from_slice(b"abc");
+        v0.extend(v0_record(&chunk_key, &chunk));
+        let mut chunk = u64::MAX.to_le_bytes().to_vec();
+        chunk.extend_from_slice(b"def");
+        v0.extend(v0_record(&chunk_key, &chunk));
+        let mut manifest = 6u64.to_le_bytes().to_vec();
+        manifest.extend_from_slice(&first_chunk.to_le_bytes());
+        v0.extend(v0_record(&crate::internal_key(large::MANIFEST_KIND, b"big"), &manifest));
+        fs::write(&path, &v0).unwrap();
+
+        assert_eq!(upgrade(&path).unwrap(), (0, FORMAT_VERSION));
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"big").unwrap(), Some(b"abcdef".to_vec()));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn headerless_stores_are_migrated_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v0.akv");
+        let mut v0 = v0_record(b"a", b"1");
+        v0.extend(v0_record(b"b", b"2"));
+        fs::write(&path, &v0).unwrap();
+
+        assert_eq!(read_version(&mut File::open(&path).unwrap()).unwrap(), Some(0));
+        assert!(ActionKV::open_read_only(&path).is_err());
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        store.insert(b"c", b"3").unwrap();
+        drop(store);
+
+        assert_eq!(read_version(&mut File::open(&path).unwrap()).unwrap(), Some(FORMAT_VERSION));
+        let mut store = ActionKV::open_read_only(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("future.akv");
+        let mut header = Vec::new();
+        write_header(&mut header, FORMAT_VERSION + 1, Checksum::default()).unwrap();
+        fs::write(&path, &header).unwrap();
+
+        let err = ActionKV::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(upgrade(&path).is_err());
+    }
+}
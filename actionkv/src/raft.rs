@@ -0,0 +1,684 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_derive::{Deserialize, Serialize};
+use crate::{ActionKV, ByteStr, ByteString};
+
+pub mod sim;
+
+pub type NodeId = u64;
+
+// Most entries sent to a follower in one message
+const MAX_ENTRIES_PER_MESSAGE: usize = 64;
+
+/// A write replicated through the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Appended by each new leader so that it has an entry of its own term
+    /// to commit
+    Noop,
+    /// `ActionKV::insert`, which deletes when the value is empty
+    Insert { key: ByteString, value: ByteString },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    RequestVote { term: u64, last_log_index: u64, last_log_term: u64 },
+    Vote { term: u64, granted: bool },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        // Echoed back, so the leader knows which of its reads a reply confirms
+        read_seq: u64,
+    },
+    AppendResult { term: u64, success: bool, match_index: u64, read_seq: u64 },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match *self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResult { term, .. } => term,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub message: Message,
+}
+
+/// Timing for a node, counted in calls to `RaftNode::tick`.
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    /// Followers wait between this and twice this many ticks without
+    /// hearing from a leader before starting an election
+    pub election_ticks: u32,
+    /// A leader sends heartbeats this often
+    pub heartbeat_ticks: u32,
+    /// Seeds the random election timeouts, together with the node's id
+    pub seed: u64,
+}
+
+impl Default for RaftOptions {
+    fn default() -> RaftOptions {
+        RaftOptions { election_ticks: 10, heartbeat_ticks: 3, seed: 0 }
+    }
+}
+
+/// Where a write or a read has got to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Pending,
+    Done,
+    /// The node lost its leadership first. The write may still be
+    /// committed by the next leader.
+    Failed,
+}
+
+/// A write accepted by a leader, at `index` in its log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Proposal {
+    pub index: u64,
+    pub term: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReadId(u64);
+
+#[derive(Debug)]
+struct PendingRead {
+    id: ReadId,
+    // Heartbeats with this sequence number or later confirm the read
+    seq: u64,
+    // The commit index the read has to wait for, once it's known
+    index: Option<u64>,
+}
+
+#[derive(Debug)]
+enum Role {
+    Follower,
+    Candidate { votes: HashSet<NodeId> },
+    Leader {
+        next_index: HashMap<NodeId, u64>,
+        match_index: HashMap<NodeId, u64>,
+        read_seq: u64,
+        // The latest read_seq each follower has replied to in this term
+        acked_seq: HashMap<NodeId, u64>,
+    },
+}
+
+/// The Raft log and the state a node has to remember across restarts,
+/// kept in a store of its own next to the data.
+///
+/// Nothing is ever removed from this store besides conflicting entries, so
+/// it grows with every write.
+///
+/// Changes aren't flushed to disk until `sync`, which has to happen before
+/// any message that depends on them is sent.
+#[derive(Debug)]
+struct RaftLog {
+    store: ActionKV,
+    entries: Vec<Entry>,
+    term: u64,
+    voted_for: Option<NodeId>,
+    applied: u64,
+    unsynced: bool,
+    // Length of the file as of the last sync, the part a power cut can't lose
+    synced_len: u64,
+}
+
+impl RaftLog {
+    fn open(path: &Path) -> io::Result<RaftLog> {
+        let mut store = ActionKV::open(path)?;
+        store.load()?;
+        let term = read_u64(&mut store, b"term")?.unwrap_or(0);
+        let voted_for = read_u64(&mut store, b"vote")?;
+        let applied = read_u64(&mut store, b"applied")?.unwrap_or(0);
+
+        let mut entries = Vec::new();
+        while let Some(entry) = store.get(&log_key(entries.len() as u64 + 1))? {
+            if entry.is_empty() {
+                break;
+            }
+            entries.push(bincode::deserialize(&entry).map_err(invalid_entry)?);
+        }
+
+        let synced_len = std::fs::metadata(path)?.len();
+        Ok(RaftLog { store, entries, term, voted_for, applied, unsynced: false, synced_len })
+    }
+
+    /// Flushes the changes made since the last sync to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.store.sync()?;
+            self.synced_len = std::fs::metadata(&self.store.path)?.len();
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, 0 before the first entry.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            _ => self.entries.get(index as usize - 1).map(|entry| entry.term),
+        }
+    }
+
+    fn set_term(&mut self, term: u64, voted_for: Option<NodeId>) -> io::Result<()> {
+        self.store.insert(b"term", &term.to_le_bytes())?;
+        match voted_for {
+            Some(id) => self.store.insert(b"vote", &id.to_le_bytes())?,
+            None => self.store.delete(b"vote")?,
+        }
+        self.term = term;
+        self.voted_for = voted_for;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    fn set_applied(&mut self, applied: u64) -> io::Result<()> {
+        self.store.insert(b"applied", &applied.to_le_bytes())?;
+        self.applied = applied;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    fn append(&mut self, entry: Entry) -> io::Result<u64> {
+        let index = self.last_index() + 1;
+        let encoded = bincode::serialize(&entry).map_err(invalid_entry)?;
+        self.store.insert(&log_key(index), &encoded)?;
+        self.entries.push(entry);
+        self.unsynced = true;
+        Ok(index)
+    }
+
+    /// Removes the entries from `index` on.
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        for i in (index..=self.last_index()).rev() {
+            self.store.delete(&log_key(i))?;
+        }
+        self.entries.truncate(index as usize - 1);
+        self.unsynced = true;
+        Ok(())
+    }
+}
+
+fn log_key(index: u64) -> ByteString {
+    let mut key = b"log".to_vec();
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn read_u64(store: &mut ActionKV, key: &ByteStr) -> io::Result<Option<u64>> {
+    match store.get(key)? {
+        Some(value) if !value.is_empty() => {
+            let bytes = value.try_into().map_err(|_| invalid_entry("malformed Raft state"))?;
+            Ok(Some(u64::from_le_bytes(bytes)))
+        },
+        _ => Ok(None),
+    }
+}
+
+fn invalid_entry<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// One member of a Raft cluster, replicating writes to an `ActionKV`.
+///
+/// The node doesn't do any I/O besides its own files: whatever runs it
+/// calls `tick` at a steady rate, hands it the messages meant for it with
+/// `step`, and delivers the ones it sends, which `take_messages` returns.
+/// That makes it easy to run several nodes in one process, as
+/// `sim::Network` does.
+///
+/// Committed entries are applied to the store in log order, so every node's
+/// store ends up with the same records. Writes go through `propose` on the
+/// leader and reads through `read_index`, which makes them linearizable.
+#[derive(Debug)]
+pub struct RaftNode {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    store: ActionKV,
+    log: RaftLog,
+    role: Role,
+    leader: Option<NodeId>,
+    commit_index: u64,
+    options: RaftOptions,
+    election_elapsed: u32,
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    rng: StdRng,
+    outbox: Vec<Envelope>,
+    reads: Vec<PendingRead>,
+    next_read: u64,
+}
+
+impl RaftNode {
+    /// Opens the node's store at `path`, and its Raft log at `path` with
+    /// `.raft` added. `peers` are the ids of the other nodes.
+    pub fn open(id: NodeId, peers: &[NodeId], path: &Path, options: RaftOptions) -> io::Result<RaftNode> {
+        let mut store = ActionKV::open(path)?;
+        store.load()?;
+        let mut log_path = path.to_path_buf().into_os_string();
+        log_path.push(".raft");
+        let log = RaftLog::open(&PathBuf::from(log_path))?;
+
+        let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(id));
+        let election_timeout = rng.gen_range(options.election_ticks..options.election_ticks * 2);
+        Ok(RaftNode {
+            id,
+            peers: peers.iter().copied().filter(|&peer| peer != id).collect(),
+            store,
+            // Entries up to `applied` were committed before the restart
+            commit_index: log.applied,
+            log,
+            role: Role::Follower,
+            leader: None,
+            options,
+            election_elapsed: 0,
+            election_timeout,
+            heartbeat_elapsed: 0,
+            rng,
+            outbox: Vec::new(),
+            reads: Vec::new(),
+            next_read: 0,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.log.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// The leader this node last heard from, if it knows of one.
+    pub fn leader(&self) -> Option<NodeId> {
+        if self.is_leader() { Some(self.id) } else { self.leader }
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// The store with every committed entry applied. Writing to it directly
+    /// would bypass replication.
+    pub fn store(&mut self) -> &mut ActionKV {
+        &mut self.store
+    }
+
+    /// Messages sent since the last call.
+    ///
+    /// The term, vote and entries they depend on are flushed to disk first,
+    /// so that a node can't take back a vote or an acknowledged entry by
+    /// crashing. If that fails the messages stay queued for the next call.
+    pub fn take_messages(&mut self) -> io::Result<Vec<Envelope>> {
+        self.log.sync()?;
+        Ok(std::mem::take(&mut self.outbox))
+    }
+
+    /// Advances the node's clock by one tick, which may start an election
+    /// or send heartbeats.
+    pub fn tick(&mut self) -> io::Result<()> {
+        if self.is_leader() {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.options.heartbeat_ticks {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append();
+            }
+            return Ok(());
+        }
+
+        self.election_elapsed += 1;
+        if self.election_elapsed >= self.election_timeout {
+            self.campaign()?;
+        }
+        Ok(())
+    }
+
+    /// Handles a message from another node.
+    pub fn step(&mut self, from: NodeId, message: Message) -> io::Result<()> {
+        if message.term() > self.log.term {
+            self.become_follower(message.term(), None)?;
+        }
+
+        match message {
+            Message::RequestVote { term, last_log_index, last_log_term } => {
+                let up_to_date = (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+                let granted = term == self.log.term
+                    && self.log.voted_for.is_none_or(|vote| vote == from)
+                    && up_to_date;
+                if granted {
+                    self.log.set_term(term, Some(from))?;
+                    self.election_elapsed = 0;
+                }
+                self.send(from, Message::Vote { term: self.log.term, granted });
+            },
+            Message::Vote { term, granted } => {
+                let quorum = self.quorum();
+                if let Role::Candidate { votes } = &mut self.role {
+                    if term == self.log.term && granted {
+                        votes.insert(from);
+                        if votes.len() >= quorum {
+                            self.become_leader()?;
+                        }
+                    }
+                }
+            },
+            Message::AppendEntries { term, prev_log_index, prev_log_term, entries, leader_commit, read_seq } => {
+                if term < self.log.term {
+                    let reply = Message::AppendResult { term: self.log.term, success: false, match_index: 0, read_seq };
+                    self.send(from, reply);
+                    return Ok(());
+                }
+                if !matches!(self.role, Role::Follower) {
+                    self.become_follower(term, Some(from))?;
+                }
+                self.leader = Some(from);
+                self.election_elapsed = 0;
+
+                let reply = self.append_entries(prev_log_index, prev_log_term, entries, leader_commit)?;
+                let (success, match_index) = match reply {
+                    Some(match_index) => (true, match_index),
+                    // Tells the leader where our log ends, to skip back faster
+                    None => (false, prev_log_index.saturating_sub(1).min(self.log.last_index())),
+                };
+                self.send(from, Message::AppendResult { term: self.log.term, success, match_index, read_seq });
+            },
+            Message::AppendResult { term, success, match_index: matched, read_seq } => {
+                if term != self.log.term {
+                    return Ok(());
+                }
+                let resend = match &mut self.role {
+                    Role::Leader { next_index, match_index, acked_seq, .. } => {
+                        let acked = acked_seq.entry(from).or_default();
+                        *acked = (*acked).max(read_seq);
+                        let next = next_index.entry(from).or_insert(1);
+                        if success {
+                            let m = match_index.entry(from).or_default();
+                            *m = (*m).max(matched);
+                            *next = *m + 1;
+                            false
+                        } else {
+                            *next = (*next - 1).min(matched + 1).max(1);
+                            true
+                        }
+                    },
+                    _ => return Ok(()),
+                };
+                if resend {
+                    self.send_append(from);
+                }
+                self.advance_commit()?;
+            },
+        }
+
+        self.apply_committed()
+    }
+
+    /// Appends a write to the leader's log. It is applied once a majority of
+    /// the cluster has it; `proposal_progress` says when.
+    pub fn propose(&mut self, command: Command) -> io::Result<Proposal> {
+        if !self.is_leader() {
+            return Err(self.not_leader());
+        }
+        // A write the store turns away would fail on every node, every time
+        // it is applied, so it never makes it into the log
+        if let Command::Insert { key, value } = &command {
+            ActionKV::check_user_key(key)?;
+            ActionKV::check_key_len(key)?;
+            ActionKV::check_value_len(value.len())?;
+        }
+        let term = self.log.term;
+        let index = self.log.append(Entry { term, command })?;
+        self.broadcast_append();
+        // A cluster of one commits straight away
+        self.advance_commit()?;
+        self.apply_committed()?;
+        Ok(Proposal { index, term })
+    }
+
+    pub fn proposal_progress(&self, proposal: &Proposal) -> Progress {
+        match self.log.term_at(proposal.index) {
+            Some(term) if term != proposal.term => Progress::Failed,
+            Some(_) if self.log.applied >= proposal.index => Progress::Done,
+            None if self.log.term > proposal.term => Progress::Failed,
+            _ if !self.is_leader() || self.log.term != proposal.term => Progress::Failed,
+            _ => Progress::Pending,
+        }
+    }
+
+    /// Starts a linearizable read. Once `read_progress` is `Done`, reading
+    /// this node's store sees every write committed before the call.
+    ///
+    /// The leader checks it is still the leader by hearing back from a
+    /// majority, so a leader cut off from the rest of the cluster can't
+    /// serve stale values.
+    pub fn read_index(&mut self) -> io::Result<ReadId> {
+        let seq = match &mut self.role {
+            Role::Leader { read_seq, .. } => {
+                *read_seq += 1;
+                *read_seq
+            },
+            _ => return Err(self.not_leader()),
+        };
+        self.next_read += 1;
+        let id = ReadId(self.next_read);
+        self.reads.push(PendingRead { id, seq, index: None });
+        self.broadcast_append();
+        Ok(id)
+    }
+
+    /// Where the read has got to. Finished reads are forgotten.
+    pub fn read_progress(&mut self, id: ReadId) -> Progress {
+        let at = match self.reads.iter().position(|read| read.id == id) {
+            Some(at) => at,
+            None => return Progress::Failed,
+        };
+
+        // The commit index only covers earlier terms' entries once the
+        // leader has committed one of its own
+        let committed_own_entry = self.log.term_at(self.commit_index) == Some(self.log.term);
+        if self.reads[at].index.is_none() && committed_own_entry && self.confirmed(self.reads[at].seq) {
+            self.reads[at].index = Some(self.commit_index);
+        }
+        match self.reads[at].index {
+            Some(index) if self.log.applied >= index => {
+                self.reads.remove(at);
+                Progress::Done
+            },
+            _ => Progress::Pending,
+        }
+    }
+
+    /// Whether a majority has replied to heartbeats sent from `seq` on.
+    fn confirmed(&self, seq: u64) -> bool {
+        match &self.role {
+            Role::Leader { acked_seq, .. } => {
+                1 + acked_seq.values().filter(|&&acked| acked >= seq).count() >= self.quorum()
+            },
+            _ => false,
+        }
+    }
+
+    fn append_entries(&mut self, prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64) -> io::Result<Option<u64>> {
+        if self.log.term_at(prev_log_index) != Some(prev_log_term) {
+            return Ok(None);
+        }
+
+        let mut index = prev_log_index;
+        for entry in entries {
+            index += 1;
+            match self.log.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(index)?,
+                None => {},
+            }
+            self.log.append(entry)?;
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(index);
+        }
+        Ok(Some(index))
+    }
+
+    fn campaign(&mut self) -> io::Result<()> {
+        let term = self.log.term + 1;
+        self.log.set_term(term, Some(self.id))?;
+        self.role = Role::Candidate { votes: HashSet::from([self.id]) };
+        self.leader = None;
+        self.reset_election_timer();
+
+        if self.quorum() == 1 {
+            return self.become_leader();
+        }
+        let (last_log_index, last_log_term) = (self.log.last_index(), self.log.last_term());
+        for peer in self.peers.clone() {
+            self.send(peer, Message::RequestVote { term, last_log_index, last_log_term });
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> io::Result<()> {
+        if term > self.log.term {
+            self.log.set_term(term, None)?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reads.clear();
+        self.reset_election_timer();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> io::Result<()> {
+        let next = self.log.last_index() + 1;
+        self.role = Role::Leader {
+            next_index: self.peers.iter().map(|&peer| (peer, next)).collect(),
+            match_index: HashMap::new(),
+            read_seq: 0,
+            acked_seq: HashMap::new(),
+        };
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        self.propose(Command::Noop)?;
+        Ok(())
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        let ticks = self.options.election_ticks;
+        self.election_timeout = self.rng.gen_range(ticks..ticks * 2);
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let (next, read_seq) = match &self.role {
+            Role::Leader { next_index, read_seq, .. } => (next_index[&peer], *read_seq),
+            _ => return,
+        };
+        let prev_log_index = next - 1;
+        let entries: Vec<Entry> = self.log.entries[prev_log_index as usize..]
+            .iter()
+            .take(MAX_ENTRIES_PER_MESSAGE)
+            .cloned()
+            .collect();
+        let message = Message::AppendEntries {
+            term: self.log.term,
+            prev_log_index,
+            prev_log_term: self.log.term_at(prev_log_index).unwrap_or(0),
+            entries,
+            leader_commit: self.commit_index,
+            read_seq,
+        };
+        self.send(peer, message);
+    }
+
+    /// Commits the latest entry of this term that a majority has.
+    fn advance_commit(&mut self) -> io::Result<()> {
+        let match_index = match &self.role {
+            Role::Leader { match_index, .. } => match_index,
+            _ => return Ok(()),
+        };
+        for index in (self.commit_index + 1..=self.log.last_index()).rev() {
+            // Entries from earlier terms are only committed along with one
+            // from this term
+            if self.log.term_at(index) != Some(self.log.term) {
+                break;
+            }
+            let replicas = 1 + match_index.values().filter(|&&m| m >= index).count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+        self.apply_committed()
+    }
+
+    fn apply_committed(&mut self) -> io::Result<()> {
+        if self.log.applied >= self.commit_index {
+            return Ok(());
+        }
+        for index in self.log.applied + 1..=self.commit_index {
+            if let Command::Insert { key, value } = &self.log.entries[index as usize - 1].command {
+                self.store.insert(key, value)?;
+            }
+        }
+        // The writes have to be on disk before the log says they were made,
+        // or a crash would skip them. Making them twice is harmless.
+        self.store.sync()?;
+        self.log.set_applied(self.commit_index)
+    }
+
+    fn quorum(&self) -> usize {
+        let cluster_size = self.peers.len() + 1;
+        cluster_size / 2 + 1
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.outbox.push(Envelope { from: self.id, to, message });
+    }
+
+    fn not_leader(&self) -> io::Error {
+        let hint = match self.leader {
+            Some(leader) => format!("; node {} is", leader),
+            None => String::new(),
+        };
+        io::Error::other(format!("node {} is not the leader{}", self.id, hint))
+    }
+}
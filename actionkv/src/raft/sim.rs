@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use crate::{ByteStr, ByteString};
+use super::{Command, Envelope, NodeId, Progress, RaftNode, RaftOptions};
+
+// How long `insert` and `get` wait for the cluster before giving up
+const REQUEST_TICKS: u32 = 500;
+
+/// Runs a cluster of `RaftNode`s in one process, with every message passing
+/// through an in-memory network that can be split up and put back together.
+///
+/// Nothing depends on the wall clock or on thread scheduling: nodes tick
+/// together, and messages are delivered in the order they were sent, one
+/// tick after sending. The same seed always plays out the same way.
+#[derive(Debug)]
+pub struct Network {
+    nodes: BTreeMap<NodeId, RaftNode>,
+    dir: PathBuf,
+    seed: u64,
+    in_flight: VecDeque<Envelope>,
+    // Links that drop every message, both ways
+    cut: HashSet<(NodeId, NodeId)>,
+}
+
+impl Network {
+    /// Starts nodes `1..=size`, keeping their files in `dir`.
+    pub fn new(dir: &Path, size: u64, seed: u64) -> io::Result<Network> {
+        let mut network = Network {
+            nodes: BTreeMap::new(),
+            dir: dir.to_path_buf(),
+            seed,
+            in_flight: VecDeque::new(),
+            cut: HashSet::new(),
+        };
+        for id in 1..=size {
+            let node = network.open_node(id, size)?;
+            network.nodes.insert(id, node);
+        }
+        Ok(network)
+    }
+
+    fn open_node(&self, id: NodeId, size: u64) -> io::Result<RaftNode> {
+        let ids: Vec<NodeId> = (1..=size).collect();
+        let options = RaftOptions { seed: self.seed, ..RaftOptions::default() };
+        let path = self.dir.join(format!("node{}.akv", id));
+        RaftNode::open(id, &ids, &path, options)
+    }
+
+    pub fn node(&mut self, id: NodeId) -> &mut RaftNode {
+        self.nodes.get_mut(&id).expect("no such node")
+    }
+
+    /// Restarts node `id` as if it had lost power: whatever it wrote to its
+    /// Raft log after the last sync is gone.
+    pub fn crash(&mut self, id: NodeId) -> io::Result<()> {
+        let node = self.nodes.remove(&id).expect("no such node");
+        let log_path = node.log.store.path.clone();
+        let synced_len = node.log.synced_len;
+        drop(node);
+        OpenOptions::new().write(true).open(log_path)?.set_len(synced_len)?;
+
+        let size = self.nodes.len() as u64 + 1;
+        let node = self.open_node(id, size)?;
+        self.nodes.insert(id, node);
+        Ok(())
+    }
+
+    /// Delivers the messages sent in the last tick, then ticks every node.
+    pub fn tick(&mut self) -> io::Result<()> {
+        for envelope in std::mem::take(&mut self.in_flight) {
+            if self.cut.contains(&(envelope.from, envelope.to)) {
+                continue;
+            }
+            if let Some(node) = self.nodes.get_mut(&envelope.to) {
+                node.step(envelope.from, envelope.message)?;
+            }
+        }
+        for node in self.nodes.values_mut() {
+            node.tick()?;
+        }
+        self.collect_messages()
+    }
+
+    pub fn run(&mut self, ticks: u32) -> io::Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// Cuts every link between `group` and the other nodes.
+    pub fn partition(&mut self, group: &[NodeId]) {
+        let ids: Vec<NodeId> = self.nodes.keys().copied().collect();
+        for &inside in group {
+            for &outside in ids.iter().filter(|id| !group.contains(id)) {
+                self.cut.insert((inside, outside));
+                self.cut.insert((outside, inside));
+            }
+        }
+    }
+
+    /// Restores every link.
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    /// The leader of the latest term, if any node thinks it is one.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes.values()
+            .filter(|node| node.is_leader())
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// Ticks until some node is leader, for at most `ticks` ticks.
+    pub fn wait_for_leader(&mut self, ticks: u32) -> io::Result<NodeId> {
+        for _ in 0..ticks {
+            if let Some(leader) = self.leader() {
+                return Ok(leader);
+            }
+            self.tick()?;
+        }
+        self.leader().ok_or_else(|| timed_out("electing a leader"))
+    }
+
+    /// Writes through node `via`, which has to be the leader, and waits for
+    /// the write to be committed.
+    pub fn insert(&mut self, via: NodeId, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let command = Command::Insert { key: key.to_vec(), value: value.to_vec() };
+        let proposal = self.node(via).propose(command)?;
+        self.collect_messages()?;
+        self.wait_for("a write", |network| network.node(via).proposal_progress(&proposal))
+    }
+
+    /// Reads through node `via`, which has to be the leader, once it has
+    /// confirmed it still is.
+    pub fn get(&mut self, via: NodeId, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let read = self.node(via).read_index()?;
+        self.collect_messages()?;
+        self.wait_for("a read", |network| network.node(via).read_progress(read))?;
+        self.node(via).store().get(key)
+    }
+
+    fn wait_for(&mut self, what: &str, mut progress: impl FnMut(&mut Network) -> Progress) -> io::Result<()> {
+        for _ in 0..REQUEST_TICKS {
+            match progress(self) {
+                Progress::Done => return Ok(()),
+                Progress::Failed => return Err(io::Error::other(format!("{} failed: the leader stepped down", what))),
+                Progress::Pending => self.tick()?,
+            }
+        }
+        Err(timed_out(what))
+    }
+
+    fn collect_messages(&mut self) -> io::Result<()> {
+        for node in self.nodes.values_mut() {
+            self.in_flight.extend(node.take_messages()?);
+        }
+        Ok(())
+    }
+}
+
+fn timed_out(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("timed out waiting for {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::Message;
+
+    #[test]
+    fn replicates_through_a_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut network = Network::new(dir.path(), 3, 7).unwrap();
+        let leader = network.wait_for_leader(100).unwrap();
+
+        network.insert(leader, b"k", b"v").unwrap();
+        assert_eq!(network.get(leader, b"k").unwrap(), Some(b"v".to_vec()));
+        network.run(10).unwrap();
+        for id in 1..=3 {
+            assert_eq!(network.node(id).store().get(b"k").unwrap(), Some(b"v".to_vec()));
+        }
+
+        let follower = (1..=3).find(|&id| id != leader).unwrap();
+        assert!(network.insert(follower, b"k", b"x").is_err());
+        assert!(network.get(follower, b"k").is_err());
+    }
+
+    #[test]
+    fn rejects_writes_the_store_would_refuse() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut network = Network::new(dir.path(), 3, 7).unwrap();
+        let leader = network.wait_for_leader(100).unwrap();
+
+        let err = network.insert(leader, b"\xffakv\x00reserved", b"v").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        network.insert(leader, b"k", b"v").unwrap();
+        network.run(10).unwrap();
+        assert_eq!(network.get(leader, b"k").unwrap(), Some(b"v".to_vec()));
+        for id in 1..=3 {
+            assert_eq!(network.node(id).store().get(b"k").unwrap(), Some(b"v".to_vec()));
+        }
+    }
+
+    #[test]
+    fn survives_partitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut network = Network::new(dir.path(), 5, 1).unwrap();
+        let old_leader = network.wait_for_leader(100).unwrap();
+        network.insert(old_leader, b"k", b"before").unwrap();
+
+        // The old leader is cut off with one follower, short of a majority
+        let buddy = (1..=5).find(|&id| id != old_leader).unwrap();
+        network.partition(&[old_leader, buddy]);
+        let write = network.node(old_leader).propose(Command::Insert { key: b"k".to_vec(), value: b"lost".to_vec() }).unwrap();
+        assert_eq!(network.get(old_leader, b"k").unwrap_err().kind(), io::ErrorKind::TimedOut);
+        network.run(100).unwrap();
+
+        let new_leader = network.leader().unwrap();
+        assert_ne!(new_leader, old_leader);
+        assert!(![old_leader, buddy].contains(&new_leader));
+        network.insert(new_leader, b"k", b"after").unwrap();
+        assert_eq!(network.get(new_leader, b"k").unwrap(), Some(b"after".to_vec()));
+
+        network.heal();
+        network.run(50).unwrap();
+        assert_eq!(network.node(old_leader).proposal_progress(&write), Progress::Failed);
+        assert!(!network.node(old_leader).is_leader());
+        for id in 1..=5 {
+            assert_eq!(network.node(id).store().get(b"k").unwrap(), Some(b"after".to_vec()));
+        }
+    }
+
+    #[test]
+    fn votes_survive_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut network = Network::new(dir.path(), 3, 5).unwrap();
+        let request = Message::RequestVote { term: 1, last_log_index: 0, last_log_term: 0 };
+
+        network.node(1).step(2, request.clone()).unwrap();
+        let sent = network.node(1).take_messages().unwrap();
+        assert_eq!(sent[0].message, Message::Vote { term: 1, granted: true });
+
+        network.crash(1).unwrap();
+        assert_eq!(network.node(1).term(), 1);
+        network.node(1).step(3, request).unwrap();
+        let sent = network.node(1).take_messages().unwrap();
+        assert_eq!(sent[0].message, Message::Vote { term: 1, granted: false });
+    }
+
+    #[test]
+    fn nodes_restart_from_their_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut network = Network::new(dir.path(), 3, 3).unwrap();
+        let leader = network.wait_for_leader(100).unwrap();
+        network.insert(leader, b"k", b"v").unwrap();
+        let term = network.node(leader).term();
+        drop(network);
+
+        let mut network = Network::new(dir.path(), 3, 3).unwrap();
+        assert_eq!(network.node(leader).term(), term);
+        let leader = network.wait_for_leader(100).unwrap();
+        assert_eq!(network.get(leader, b"k").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(network.node(leader).store().scan(b"").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use serde_derive::{Deserialize, Serialize};
+use xxhash_rust::xxh64::xxh64;
+use crate::{ActionKV, ByteStr, ByteString, KeyValuePair};
+
+// Points each shard gets on the hash ring. More points spread keys more
+// evenly between shards.
+const POINTS_PER_SHARD: u32 = 64;
+
+const LAYOUT_FILE: &str = "layout.json";
+
+/// Which shard each key belongs to. Adding or removing a shard only moves
+/// the keys next to its points on the ring, about `1 / shards` of them.
+#[derive(Debug)]
+struct Ring {
+    shards: u32,
+    points: BTreeMap<u64, u32>,
+}
+
+impl Ring {
+    fn new(shards: u32) -> Ring {
+        let mut points = BTreeMap::new();
+        for shard in 0..shards {
+            for point in 0..POINTS_PER_SHARD {
+                let name = [shard.to_le_bytes(), point.to_le_bytes()].concat();
+                points.insert(xxh64(&name, 0), shard);
+            }
+        }
+        Ring { shards, points }
+    }
+
+    fn owner(&self, key: &ByteStr) -> u32 {
+        let hash = xxh64(key, 0);
+        let (_, &shard) = self.points.range(hash..).next()
+            .or_else(|| self.points.iter().next())
+            .unwrap();
+        shard
+    }
+}
+
+/// What `layout.json` holds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct LayoutFile {
+    shards: u32,
+    // Set while keys are moving from a layout with this many shards
+    resharding_from: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Layout {
+    ring: Ring,
+    // The ring keys are moving away from, while resharding
+    previous: Option<Ring>,
+    // Enough for both rings
+    shards: Vec<Mutex<ActionKV>>,
+}
+
+impl Layout {
+    fn shard(&self, shard: u32) -> MutexGuard<'_, ActionKV> {
+        self.shards[shard as usize].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Spreads keys over several `ActionKV` files in a directory, each with its
+/// own lock, so that writes to different shards run in parallel.
+///
+/// Keys are assigned to shards by consistent hashing. `reshard` changes the
+/// number of shards while the store stays in use.
+#[derive(Debug)]
+pub struct ShardedStore {
+    dir: PathBuf,
+    layout: RwLock<Layout>,
+    // Only one reshard runs at a time
+    resharding: Mutex<()>,
+}
+
+impl ShardedStore {
+    /// Opens the sharded store in `dir`, creating it with `shards` shards if
+    /// needed. Existing stores keep the number of shards they have.
+    ///
+    /// A reshard that was interrupted is finished before this returns.
+    pub fn open(dir: &Path, shards: u32) -> io::Result<ShardedStore> {
+        if shards == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a sharded store needs at least one shard"));
+        }
+        fs::create_dir_all(dir)?;
+        let file = match fs::read(dir.join(LAYOUT_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let file = LayoutFile { shards, resharding_from: None };
+                write_layout(dir, file)?;
+                file
+            },
+            Err(err) => return Err(err),
+        };
+
+        let open_shards = file.shards.max(file.resharding_from.unwrap_or(0));
+        let mut shards = Vec::with_capacity(open_shards as usize);
+        for shard in 0..open_shards {
+            let mut store = ActionKV::open(&shard_path(dir, shard))?;
+            store.load()?;
+            shards.push(Mutex::new(store));
+        }
+        let layout = Layout {
+            ring: Ring::new(file.shards),
+            previous: file.resharding_from.map(Ring::new),
+            shards,
+        };
+
+        let store = ShardedStore { dir: dir.to_path_buf(), layout: RwLock::new(layout), resharding: Mutex::new(()) };
+        if let Some(from) = file.resharding_from {
+            store.move_keys(from, file.shards)?;
+        }
+        Ok(store)
+    }
+
+    pub fn shards(&self) -> u32 {
+        self.read_layout().ring.shards
+    }
+
+    pub fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let layout = self.read_layout();
+        let owner = layout.ring.owner(key);
+        let mut shard = layout.shard(owner);
+        let value = shard.get(key)?;
+        // Keys that haven't moved yet are still in their old shard. Holding
+        // the new shard's lock, as `move_keys` does, keeps the key from
+        // moving between the two reads.
+        match &layout.previous {
+            Some(previous) if value.is_none() && previous.owner(key) != owner => {
+                let mut old_shard = layout.shard(previous.owner(key));
+                old_shard.get(key)
+            },
+            _ => Ok(value),
+        }
+    }
+
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let layout = self.read_layout();
+        let mut shard = layout.shard(layout.ring.owner(key));
+        shard.insert(key, value)
+    }
+
+    #[inline]
+    pub fn delete(&self, key: &ByteStr) -> io::Result<()> {
+        self.insert(key, b"")
+    }
+
+    pub fn update(&self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.insert(key, value)
+    }
+
+    /// Like `ActionKV::scan`, across every shard.
+    pub fn scan(&self, prefix: &ByteStr) -> io::Result<Vec<KeyValuePair>> {
+        let keys: BTreeSet<ByteString> = {
+            let layout = self.read_layout();
+            let mut keys = BTreeSet::new();
+            for shard in 0..layout.shards.len() as u32 {
+                keys.extend(layout.shard(shard).keys_with_prefix(prefix));
+            }
+            keys
+        };
+
+        let mut pairs = Vec::new();
+        for key in keys {
+            let value = self.get(&key)?.unwrap_or_default();
+            if !value.is_empty() {
+                pairs.push(KeyValuePair { key, value });
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Changes the number of shards to `shards`, moving the keys whose shard
+    /// changes. Reads and writes carry on while keys move: writes go to the
+    /// new shards, and reads look in the old shard for keys that haven't
+    /// moved yet.
+    ///
+    /// If the process stops part way, `open` finishes the job.
+    pub fn reshard(&self, shards: u32) -> io::Result<()> {
+        if shards == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a sharded store needs at least one shard"));
+        }
+        let _resharding = self.resharding.lock().unwrap_or_else(PoisonError::into_inner);
+        let from = {
+            let mut layout = self.layout.write().unwrap_or_else(PoisonError::into_inner);
+            let from = layout.ring.shards;
+            if from == shards {
+                return Ok(());
+            }
+            write_layout(&self.dir, LayoutFile { shards, resharding_from: Some(from) })?;
+            for shard in layout.shards.len() as u32..shards {
+                let mut store = ActionKV::open(&shard_path(&self.dir, shard))?;
+                store.load()?;
+                layout.shards.push(Mutex::new(store));
+            }
+            layout.previous = Some(std::mem::replace(&mut layout.ring, Ring::new(shards)));
+            from
+        };
+        self.move_keys(from, shards)
+    }
+
+    /// Copies the keys whose shard differs between the two rings to their
+    /// new shard, then removes them from the old one.
+    fn move_keys(&self, from: u32, to: u32) -> io::Result<()> {
+        {
+            let layout = self.read_layout();
+            let previous = layout.previous.as_ref().unwrap();
+            for shard in 0..from {
+                let keys = layout.shard(shard).keys_with_prefix(b"");
+                let moving: Vec<_> = keys.into_iter().filter(|key| layout.ring.owner(key) != shard).collect();
+                for key in &moving {
+                    debug_assert_eq!(previous.owner(key), shard);
+                    // Holding the new shard's lock keeps writes to the key
+                    // from landing between the check and the copy. Anything
+                    // the new shard has, deletes included, is newer.
+                    let mut new_shard = layout.shard(layout.ring.owner(key));
+                    if new_shard.get(key)?.is_none() {
+                        let value = layout.shard(shard).get(key)?.filter(|value| !value.is_empty());
+                        if let Some(value) = value {
+                            new_shard.insert(key, &value)?;
+                        }
+                    }
+                }
+
+                // Compaction drops the deletes too, so that they can't be
+                // mistaken for newer values if the keys ever move back
+                let mut old_shard = layout.shard(shard);
+                for key in &moving {
+                    old_shard.delete(key)?;
+                }
+                if !moving.is_empty() && shard < to {
+                    old_shard.compact()?;
+                }
+            }
+        }
+
+        let mut layout = self.layout.write().unwrap_or_else(PoisonError::into_inner);
+        layout.previous = None;
+        write_layout(&self.dir, LayoutFile { shards: to, resharding_from: None })?;
+        // Shards beyond the new count are empty now
+        layout.shards.truncate(to as usize);
+        for shard in to..from {
+            fs::remove_file(shard_path(&self.dir, shard))?;
+        }
+        Ok(())
+    }
+
+    fn read_layout(&self) -> RwLockReadGuard<'_, Layout> {
+        self.layout.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn shard_path(dir: &Path, shard: u32) -> PathBuf {
+    dir.join(format!("shard-{}.akv", shard))
+}
+
+/// Replaces `layout.json` in one step, so a crash leaves the old or the new
+/// layout but never half of one.
+///
+/// The new file is synced before the rename, and the directory after it,
+/// so that the rename can't reach the disk before the contents or be lost.
+fn write_layout(dir: &Path, file: LayoutFile) -> io::Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", LAYOUT_FILE));
+    let mut tmp = fs::File::create(&tmp_path)?;
+    tmp.write_all(&serde_json::to_vec(&file)?)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(LAYOUT_FILE))?;
+    sync_dir(dir)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+// Only unix lets a directory be opened and synced like a file
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn ring_moves_few_keys() {
+        let (three, four) = (Ring::new(3), Ring::new(4));
+        let keys: Vec<ByteString> = (0..1000).map(|i: u32| i.to_le_bytes().to_vec()).collect();
+        let moved = keys.iter().filter(|key| three.owner(key) != four.owner(key)).count();
+        assert!(moved > 100 && moved < 400, "{} keys moved", moved);
+        for shard in 0..3 {
+            assert!(keys.iter().filter(|key| three.owner(key) == shard).count() > 200);
+        }
+    }
+
+    #[test]
+    fn parallel_writes_and_online_resharding() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(ShardedStore::open(dir.path(), 4).unwrap());
+        for i in 0..200u32 {
+            store.insert(format!("key{}", i).as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        store.delete(b"key7").unwrap();
+
+        // Writers keep going while the store grows
+        let writers: Vec<_> = (0..4u32).map(|t| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                for i in (t..200).step_by(4) {
+                    store.insert(format!("key{}", i).as_bytes(), &(i + 1000).to_le_bytes()).unwrap();
+                }
+            })
+        }).collect();
+        store.reshard(7).unwrap();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(store.shards(), 7);
+        assert_eq!(store.get(b"key3").unwrap(), Some(1003u32.to_le_bytes().to_vec()));
+        assert_eq!(store.scan(b"key").unwrap().len(), 200);
+        drop(store);
+
+        let store = ShardedStore::open(dir.path(), 1).unwrap();
+        store.reshard(2).unwrap();
+        assert_eq!(store.shards(), 2);
+        assert!(!dir.path().join("shard-2.akv").exists());
+        let pairs = store.scan(b"").unwrap();
+        assert_eq!(pairs.len(), 200);
+        assert!(pairs.iter().all(|kv| kv.value.len() == 4 && u32::from_le_bytes(kv.value[..].try_into().unwrap()) >= 1000));
+    }
+
+    #[test]
+    fn open_finishes_an_interrupted_reshard() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ShardedStore::open(dir.path(), 2).unwrap();
+        for i in 0..50u32 {
+            store.insert(&i.to_le_bytes(), b"v").unwrap();
+        }
+        drop(store);
+        write_layout(dir.path(), LayoutFile { shards: 3, resharding_from: Some(2) }).unwrap();
+
+        let store = ShardedStore::open(dir.path(), 2).unwrap();
+        assert_eq!(store.shards(), 3);
+        let ring = Ring::new(3);
+        for i in 0..50u32 {
+            let key = i.to_le_bytes();
+            assert_eq!(store.read_layout().shard(ring.owner(&key)).get(&key).unwrap(), Some(b"v".to_vec()));
+        }
+        assert_eq!(store.scan(b"").unwrap().len(), 50);
+    }
+}
@@ -0,0 +1,276 @@
+use std::io;
+use std::io::{BufRead, IsTerminal, Write};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use crate::{ActionKV, ByteStr, ByteString};
+
+const HELP: &str = r#"Commands:
+    get KEY
+    insert KEY VALUE
+    update KEY VALUE
+    delete KEY
+    scan [PREFIX]
+    stats
+    compact
+    help
+    exit
+
+Arguments are separated by whitespace. Use "double" or 'single' quotes for
+arguments containing spaces and \xNN escapes for arbitrary bytes.
+"#;
+
+/// What the shell should do after running a command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Exit,
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn hex_digit(c: char) -> Option<u8> {
+    c.to_digit(16).map(|d| d as u8)
+}
+
+/// Splits a command line into its arguments.
+///
+/// Arguments are separated by whitespace unless quoted. Outside single
+/// quotes, a backslash starts an escape: `\xNN` for any byte, `\n`, `\r`,
+/// `\t`, `\0`, or a backslash followed by any other character for that
+/// character itself.
+pub fn tokenize(line: &str) -> io::Result<Vec<ByteString>> {
+    let mut args = Vec::new();
+    let mut current: Option<ByteString> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => push_char(current.get_or_insert_with(Vec::new), c),
+            (_, '\\') => {
+                let byte = match chars.next() {
+                    None => return Err(invalid_input("trailing backslash".to_string())),
+                    Some('n') => b'\n',
+                    Some('r') => b'\r',
+                    Some('t') => b'\t',
+                    Some('0') => b'\0',
+                    Some('x') => {
+                        let hi = chars.next().and_then(hex_digit);
+                        let lo = chars.next().and_then(hex_digit);
+                        match (hi, lo) {
+                            (Some(hi), Some(lo)) => hi << 4 | lo,
+                            _ => return Err(invalid_input("\\x must be followed by two hex digits".to_string())),
+                        }
+                    },
+                    Some(other) => {
+                        push_char(current.get_or_insert_with(Vec::new), other);
+                        continue;
+                    },
+                };
+                current.get_or_insert_with(Vec::new).push(byte);
+            },
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                current.get_or_insert_with(Vec::new);
+            },
+            (None, c) if c.is_whitespace() => {
+                if let Some(arg) = current.take() {
+                    args.push(arg);
+                }
+            },
+            (_, c) => push_char(current.get_or_insert_with(Vec::new), c),
+        }
+    }
+
+    if let Some(q) = quote {
+        return Err(invalid_input(format!("unterminated {} quote", q)));
+    }
+    if let Some(arg) = current {
+        args.push(arg);
+    }
+
+    Ok(args)
+}
+
+fn push_char(buf: &mut ByteString, c: char) {
+    let mut utf8 = [0; 4];
+    buf.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+}
+
+/// Renders bytes so that they can be pasted back into the shell: printable
+/// ASCII is kept as is and everything else becomes a `\xNN` escape.
+pub fn escape(bytes: &ByteStr) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b'"' => escaped.push_str("\\\""),
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Runs a single tokenized command against the store.
+pub fn execute<W: Write>(store: &mut ActionKV, args: &[ByteString], out: &mut W) -> io::Result<Flow> {
+    let (command, args) = match args.split_first() {
+        None => return Ok(Flow::Continue),
+        Some((command, args)) => (command.as_slice(), args),
+    };
+
+    let arity = |n: usize| -> io::Result<()> {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(invalid_input(format!(
+                "{} takes {} argument(s), got {}",
+                String::from_utf8_lossy(command), n, args.len()
+            )))
+        }
+    };
+
+    match command {
+        b"get" => {
+            arity(1)?;
+            match store.get(&args[0])? {
+                None => writeln!(out, "(not found)")?,
+                Some(value) => writeln!(out, "\"{}\"", escape(&value))?,
+            }
+        },
+        b"insert" | b"update" => {
+            arity(2)?;
+            store.insert(&args[0], &args[1])?;
+        },
+        b"delete" => {
+            arity(1)?;
+            store.delete(&args[0])?;
+        },
+        b"scan" => {
+            let prefix: &ByteStr = match args {
+                [] => b"",
+                [prefix] => prefix,
+                _ => return Err(invalid_input("scan takes at most one argument".to_string())),
+            };
+            for kv in store.scan(prefix)? {
+                writeln!(out, "\"{}\" => \"{}\"", escape(&kv.key), escape(&kv.value))?;
+            }
+        },
+        b"stats" => {
+            arity(0)?;
+            write!(out, "{}", store.stats()?)?;
+        },
+        b"compact" => {
+            arity(0)?;
+            store.compact()?;
+        },
+        b"help" => write!(out, "{}", HELP)?,
+        b"exit" | b"quit" => return Ok(Flow::Exit),
+        _ => return Err(invalid_input(format!(
+            "unknown command \"{}\", try help",
+            escape(command)
+        ))),
+    }
+
+    Ok(Flow::Continue)
+}
+
+/// Runs the shell, reading commands with line editing when stdin is a
+/// terminal and as a batch script otherwise.
+pub fn run(store: &mut ActionKV) -> io::Result<()> {
+    if io::stdin().is_terminal() {
+        run_interactive(store)
+    } else {
+        run_script(store, io::stdin().lock(), &mut io::stdout())
+    }
+}
+
+fn run_interactive(store: &mut ActionKV) -> io::Result<()> {
+    let mut editor = DefaultEditor::new().map_err(io::Error::other)?;
+    let mut stdout = io::stdout();
+
+    loop {
+        let line = match editor.readline("akv> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(io::Error::other(err)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        let result = tokenize(&line).and_then(|args| execute(store, &args, &mut stdout));
+        match result {
+            Ok(Flow::Exit) => break,
+            Ok(Flow::Continue) => {},
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs one command per line from `input`, stopping at the first command
+/// that fails. Blank lines and lines starting with `#` are ignored.
+pub fn run_script<R: BufRead, W: Write>(store: &mut ActionKV, input: R, out: &mut W) -> io::Result<()> {
+    for (line_no, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let result = tokenize(&line).and_then(|args| execute(store, &args, out));
+        match result {
+            Ok(Flow::Exit) => break,
+            Ok(Flow::Continue) => {},
+            Err(err) => return Err(io::Error::new(err.kind(), format!("line {}: {}", line_no + 1, err))),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_handles_quotes_and_escapes() {
+        let args = tokenize(r#"insert "a key" 'raw \x00' \xff\x00bin"#).unwrap();
+        assert_eq!(args, vec![
+            b"insert".to_vec(),
+            b"a key".to_vec(),
+            b"raw \\x00".to_vec(),
+            vec![0xff, 0x00, b'b', b'i', b'n'],
+        ]);
+        assert_eq!(tokenize(r#"get """#).unwrap(), vec![b"get".to_vec(), vec![]]);
+        assert!(tokenize("get \"open").is_err());
+        assert!(tokenize("get \\xZ1").is_err());
+    }
+
+    #[test]
+    fn escape_round_trips_through_tokenize() {
+        let raw: ByteString = (0..=255).collect();
+        let line = format!("\"{}\"", escape(&raw));
+        assert_eq!(tokenize(&line).unwrap(), vec![raw]);
+    }
+
+    #[test]
+    fn script_runs_against_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("shell.akv")).unwrap();
+        store.load().unwrap();
+
+        let script = b"# comment\ninsert a 1\ninsert b 2\ndelete a\nscan\nget b\n";
+        let mut out = Vec::new();
+        run_script(&mut store, &script[..], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\"b\" => \"2\"\n\"2\"\n");
+
+        let err = run_script(&mut store, &b"get\n"[..], &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().starts_with("line 1:"));
+    }
+}
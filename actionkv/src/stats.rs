@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{BufReader, Seek, SeekFrom};
+use crate::large::Manifest;
+use crate::{cf, CacheStats, large, merge, parse_internal_key, ActionKV, ByteString, RECORD_HEADER_LEN};
+
+/// How many of the biggest live keys `stats` reports.
+const LARGEST_KEYS: usize = 10;
+
+/// Space amplification above which compaction is worth running.
+pub const COMPACTION_THRESHOLD: f64 = 2.0;
+
+/// Counts sizes in power-of-two buckets. Bucket 0 holds empty entries and
+/// bucket `i` holds sizes in `2^(i-1)..2^i`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, size: u64) {
+        let bucket = (u64::BITS - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// Yields `(largest size in bucket, count)` for each non-empty bucket.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets.iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| {
+                let upper = if bucket == 0 { 0 } else { (1u64 << bucket) - 1 };
+                (upper, *count)
+            })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StoreStats {
+    /// Every record in the file, including overwritten ones and tombstones
+    pub total_records: u64,
+    /// Keys whose latest record holds a non-empty value
+    pub live_keys: u64,
+    pub file_bytes: u64,
+    pub live_bytes: u64,
+    /// Bytes that compaction would reclaim
+    pub stale_bytes: u64,
+    pub key_sizes: Histogram,
+    pub value_sizes: Histogram,
+    /// The live keys taking up the most space, with their record size
+    pub largest_keys: Vec<(ByteString, u64)>,
+    /// How the value cache has done, for stores opened with one
+    pub cache: Option<CacheStats>,
+}
+
+impl StoreStats {
+    /// File size divided by the size of the live data. A freshly compacted
+    /// store has an amplification of 1.0.
+    pub fn space_amplification(&self) -> f64 {
+        if self.live_bytes == 0 {
+            if self.file_bytes == 0 { 1.0 } else { f64::INFINITY }
+        } else {
+            self.file_bytes as f64 / self.live_bytes as f64
+        }
+    }
+
+    pub fn compaction_recommended(&self) -> bool {
+        self.space_amplification() >= COMPACTION_THRESHOLD
+    }
+}
+
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "records:             {}", self.total_records)?;
+        writeln!(f, "live keys:           {}", self.live_keys)?;
+        writeln!(f, "file bytes:          {}", self.file_bytes)?;
+        writeln!(f, "live bytes:          {}", self.live_bytes)?;
+        writeln!(f, "stale bytes:         {}", self.stale_bytes)?;
+        writeln!(f, "space amplification: {:.2}", self.space_amplification())?;
+        writeln!(f, "compaction:          {}", if self.compaction_recommended() { "recommended" } else { "not needed" })?;
+
+        for (name, histogram) in [("key sizes", &self.key_sizes), ("value sizes", &self.value_sizes)] {
+            writeln!(f, "{}:", name)?;
+            for (upper, count) in histogram.iter() {
+                writeln!(f, "    <= {:<12} {}", upper, count)?;
+            }
+        }
+
+        if let Some(cache) = &self.cache {
+            writeln!(f, "cache hits:          {}", cache.hits)?;
+            writeln!(f, "cache misses:        {}", cache.misses)?;
+            writeln!(f, "cache bytes:         {} of {}", cache.bytes, cache.capacity)?;
+        }
+
+        writeln!(f, "largest keys:")?;
+        for (key, size) in &self.largest_keys {
+            writeln!(f, "    {:<12} \"{}\"", size, crate::shell::escape(key))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ActionKV {
+    /// Reads the whole file and reports how much of it is still live.
+    pub fn stats(&mut self) -> io::Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        // key => latest record seen for it
+        let mut latest: HashMap<ByteString, Record> = HashMap::new();
+        // position => (record size, next chunk) for every chunk of a large value
+        let mut chunks: HashMap<u64, (u64, Option<u64>)> = HashMap::new();
+
+        let mut largest = Vec::new();
+
+        let end = self.file.metadata()?.len();
+        let mut f = BufReader::new(&mut self.file);
+        f.seek(SeekFrom::Start(self.data_start))?;
+        loop {
+            let position = f.stream_position()?;
+            let kv = match ActionKV::process_record(&mut f, self.checksum, position, end) {
+                Ok(kv) => kv,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            let record_len = RECORD_HEADER_LEN + (kv.key.len() + kv.value.len()) as u64;
+            stats.total_records += 1;
+            stats.file_bytes += record_len;
+
+            // Column family records are live if their column family's index
+            // still points at them
+            match cf::is_live_record(&self.column_families, &kv, position) {
+                Some(true) if cf::parse_cf_key(&kv.key).is_some() => {
+                    stats.live_keys += 1;
+                    stats.live_bytes += record_len;
+                    stats.key_sizes.record(kv.key.len() as u64);
+                    stats.value_sizes.record(kv.value.len() as u64);
+                    largest.push((kv.key, record_len));
+                    continue;
+                },
+                Some(true) => {
+                    stats.live_bytes += record_len;
+                    continue;
+                },
+                Some(false) => continue,
+                None => {},
+            }
+
+            match parse_internal_key(&kv.key) {
+                Some((large::CHUNK_KIND, _)) => {
+                    chunks.insert(position, (record_len, large::chunk_next(&kv.value)?));
+                },
+                Some((merge::MERGE_KIND, user_key)) => {
+                    // Operands are live until the key's value is set again
+                    let pending = self.merges.get(user_key).is_some_and(|positions| positions.contains(&position));
+                    if pending {
+                        stats.live_bytes += record_len;
+                    }
+                },
+                Some((large::MANIFEST_KIND, _)) => {
+                    let manifest = Manifest::decode(&kv.value)?;
+                    latest.insert(kv.key, Record { position, record_len, value_len: manifest.total_len, manifest: Some(manifest) });
+                },
+                _ => {
+                    latest.insert(kv.key, Record { position, record_len, value_len: kv.value.len() as u64, manifest: None });
+                },
+            }
+        }
+
+        // A key's current value is in whichever of its plain record and its
+        // large value manifest was written last
+        let mut current: HashMap<ByteString, Record> = HashMap::new();
+        for (key, record) in latest {
+            let user_key = match parse_internal_key(&key) {
+                Some((_, user_key)) => user_key.to_vec(),
+                None => key,
+            };
+            match current.get(&user_key) {
+                Some(existing) if existing.position > record.position => {},
+                _ => { current.insert(user_key, record); },
+            }
+        }
+
+        for (key, record) in current {
+            if record.value_len == 0 {
+                continue;
+            }
+            let mut live_len = record.record_len;
+            if let Some(manifest) = record.manifest {
+                let mut next = Some(manifest.first_chunk);
+                while let Some((chunk_len, following)) = next.and_then(|position| chunks.get(&position)) {
+                    live_len += chunk_len;
+                    next = *following;
+                }
+            }
+
+            stats.live_keys += 1;
+            stats.live_bytes += live_len;
+            stats.key_sizes.record(key.len() as u64);
+            stats.value_sizes.record(record.value_len);
+            largest.push((key, live_len));
+        }
+        stats.stale_bytes = stats.file_bytes - stats.live_bytes;
+
+        largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        largest.truncate(LARGEST_KEYS);
+        stats.largest_keys = largest;
+        stats.cache = self.cache.as_ref().map(|cache| cache.stats());
+
+        Ok(stats)
+    }
+}
+
+struct Record {
+    position: u64,
+    record_len: u64,
+    value_len: u64,
+    manifest: Option<Manifest>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_by_power_of_two() {
+        let mut histogram = Histogram::default();
+        for size in [0, 1, 2, 3, 4, 1000] {
+            histogram.record(size);
+        }
+        let buckets: Vec<_> = histogram.iter().collect();
+        assert_eq!(buckets, vec![(0, 1), (1, 1), (3, 2), (7, 1), (1023, 1)]);
+    }
+
+    #[test]
+    fn stats_counts_stale_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("stats.akv")).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"22").unwrap();
+        store.insert(b"b", b"333").unwrap();
+        store.delete(b"b").unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.total_records, 4);
+        assert_eq!(stats.live_keys, 1);
+        assert_eq!(stats.live_bytes, RECORD_HEADER_LEN + 3);
+        assert_eq!(stats.stale_bytes, stats.file_bytes - stats.live_bytes);
+        assert_eq!(stats.largest_keys, vec![(b"a".to_vec(), RECORD_HEADER_LEN + 3)]);
+        assert!(stats.compaction_recommended());
+
+        store.compact().unwrap();
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.stale_bytes, 0);
+        assert_eq!(stats.space_amplification(), 1.0);
+
+        store.insert_chunked(b"c", &[1u8; 40][..], 16).unwrap();
+        let chunked = store.stats().unwrap();
+        assert_eq!(chunked.live_keys, 2);
+        assert_eq!(chunked.largest_keys[0].0, b"c".to_vec());
+        assert_eq!(chunked.value_sizes.iter().last(), Some((63, 1)));
+        assert_eq!(chunked.stale_bytes, 0);
+    }
+}
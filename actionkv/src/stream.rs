@@ -0,0 +1,373 @@
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use byteorder::{LittleEndian, WriteBytesExt};
+use crate::checksum;
+use crate::checksum::{corruption, Checksum, RecordHasher, RecordHeader};
+use crate::large::{chunk_next, Location, Manifest, CHUNK_LINK_LEN};
+use crate::{ActionKV, ByteStr, ByteString, MAX_VALUE_LEN, RECORD_HEADER_LEN};
+
+const CATCH_UP_BUF_LEN: usize = 8 * 1024;
+
+/// The data region of one record. Its checksum is computed as the region is
+/// read front to back and checked once the last byte has been read.
+struct Segment {
+    // Where the segment starts within the value
+    value_offset: u64,
+    position: u64,
+    data_start: u64,
+    data_len: u64,
+    saved_checksum: u64,
+    hasher: RecordHasher,
+    // Bytes of the data region fed to `hasher` so far
+    hashed: u64,
+}
+
+impl Segment {
+    /// Reads a record's header and key, leaving the value unread. `link_len`
+    /// bytes at the start of the value are hashed but not part of the data.
+    fn open(file: &mut File, checksum: Checksum, position: u64, link_len: usize) -> io::Result<(Segment, ByteString)> {
+        let end = file.metadata()?.len();
+        file.seek(SeekFrom::Start(position))?;
+        let RecordHeader { checksum: saved_checksum, key_len, value_len, .. } = RecordHeader::read(file, position)?;
+
+        let remaining = end.saturating_sub(position + RECORD_HEADER_LEN);
+        if key_len as u64 + value_len as u64 > remaining || (value_len as usize) < link_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record at {} has invalid lengths", position)
+            ));
+        }
+
+        let mut prefix = vec![0; key_len as usize + link_len];
+        file.read_exact(&mut prefix)?;
+        let mut hasher = checksum.hasher();
+        hasher.lengths(key_len, value_len);
+        hasher.write(&prefix);
+
+        let segment = Segment {
+            value_offset: 0,
+            position,
+            data_start: position + RECORD_HEADER_LEN + prefix.len() as u64,
+            data_len: value_len as u64 - link_len as u64,
+            saved_checksum,
+            hasher,
+            hashed: 0,
+        };
+        segment.verify_if_done()?;
+
+        let link = prefix.split_off(key_len as usize);
+        Ok((segment, link))
+    }
+
+    fn hash(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.write(data);
+        self.hashed += data.len() as u64;
+        self.verify_if_done()
+    }
+
+    fn verify_if_done(&self) -> io::Result<()> {
+        if self.hashed == self.data_len && self.hasher.finish() != self.saved_checksum {
+            return Err(corruption(self.position, self.hasher.finish(), self.saved_checksum));
+        }
+        Ok(())
+    }
+
+    /// Hashes the part of the segment that a seek skipped over.
+    fn catch_up(&mut self, file: &mut File, until: u64) -> io::Result<()> {
+        let mut buf = [0; CATCH_UP_BUF_LEN];
+        file.seek(SeekFrom::Start(self.data_start + self.hashed))?;
+        while self.hashed < until {
+            let want = cmp::min(buf.len() as u64, until - self.hashed) as usize;
+            file.read_exact(&mut buf[..want])?;
+            self.hash(&buf[..want])?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a value in place from the store file without loading it into
+/// memory. Values written with `insert_large` are read chunk by chunk.
+///
+/// Every record's CRC is checked once its last byte has been read, so
+/// corruption is reported by the `read` call that reaches the end of the
+/// damaged record.
+pub struct ValueReader<'a> {
+    file: &'a mut File,
+    segments: Vec<Segment>,
+    // Values with merges only exist once the merges are applied, so they
+    // are read from memory
+    merged: Option<ByteString>,
+    len: u64,
+    position: u64,
+}
+
+impl ValueReader<'_> {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(value) = &self.merged {
+            let n = (&value[self.position as usize..]).read(buf)?;
+            self.position += n as u64;
+            return Ok(n);
+        }
+
+        let index = self.segments.partition_point(|s| s.value_offset <= self.position) - 1;
+        let segment = &mut self.segments[index];
+        let offset = self.position - segment.value_offset;
+        if offset > segment.hashed {
+            segment.catch_up(self.file, offset)?;
+        }
+
+        let want = cmp::min(buf.len() as u64, segment.data_len - offset) as usize;
+        self.file.seek(SeekFrom::Start(segment.data_start + offset))?;
+        let n = self.file.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        if offset == segment.hashed {
+            segment.hash(&buf[..n])?;
+        }
+        self.position += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for ValueReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match target {
+            Some(target) => {
+                self.position = target;
+                Ok(target)
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position"
+            )),
+        }
+    }
+}
+
+impl ActionKV {
+    /// Returns a reader over the value of `key` that reads the record in
+    /// place instead of materialising it like `get` does. Values with merges
+    /// are the exception, as the merges have to be applied first.
+    pub fn get_reader(&mut self, key: &ByteStr) -> io::Result<Option<ValueReader<'_>>> {
+        if self.merges.contains_key(key) {
+            return Ok(self.get_merged(key)?.map(|value| ValueReader {
+                file: &mut self.file,
+                segments: Vec::new(),
+                len: value.len() as u64,
+                merged: Some(value),
+                position: 0,
+            }));
+        }
+
+        let mut segments = Vec::new();
+        match self.locate(key) {
+            None => return Ok(None),
+            Some(Location::Record(position)) => {
+                segments.push(Segment::open(&mut self.file, self.checksum, position, 0)?.0);
+            },
+            Some(Location::Large(position)) => {
+                let manifest = Manifest::decode(&self.get_at(position)?.value)?;
+                let mut value_offset = 0;
+                let mut next = Some(manifest.first_chunk);
+                while let Some(position) = next {
+                    let (mut segment, link) = Segment::open(&mut self.file, self.checksum, position, CHUNK_LINK_LEN)?;
+                    segment.value_offset = value_offset;
+                    value_offset += segment.data_len;
+                    next = chunk_next(&link)?;
+                    segments.push(segment);
+                }
+            },
+        }
+
+        // Empty chunks can't hold any position, so they only get in the way
+        // of finding a position's segment
+        segments.retain(|s| s.data_len > 0);
+        let len = segments.last().map(|s| s.value_offset + s.data_len).unwrap_or(0);
+
+        Ok(Some(ValueReader { file: &mut self.file, segments, merged: None, len, position: 0 }))
+    }
+
+    /// Stores exactly `len` bytes read from `value` as a single record,
+    /// streaming them to disk instead of buffering them in memory.
+    ///
+    /// If `value` ends early or fails, the partial record is truncated away
+    /// and the store is left as it was.
+    pub fn insert_from_reader<R: Read>(&mut self, key: &ByteStr, value: R, len: u64) -> io::Result<()> {
+        ActionKV::check_user_key(key)?;
+        ActionKV::check_key_len(key)?;
+        self.check_writable()?;
+        if len > MAX_VALUE_LEN as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("value is {} bytes, the limit is {} bytes; use insert_large for bigger values", len, MAX_VALUE_LEN)
+            ));
+        }
+
+        let started = Instant::now();
+        let position = self.file.seek(SeekFrom::End(0))?;
+        match self.stream_record(key, value, len, position) {
+            Ok(()) => {
+                self.index_key(key.to_vec(), position);
+                self.metrics.bytes_written.fetch_add(RECORD_HEADER_LEN + key.len() as u64 + len, Ordering::Relaxed);
+                self.metrics.record_write(len, started);
+                Ok(())
+            },
+            Err(err) => {
+                self.file.set_len(position)?;
+                Err(err)
+            },
+        }
+    }
+
+    fn stream_record<R: Read>(&mut self, key: &ByteStr, value: R, len: u64, position: u64) -> io::Result<()> {
+        let mut hasher = self.checksum.hasher();
+        hasher.lengths(key.len() as u32, len as u32);
+        hasher.write(key);
+
+        // The checksum comes first in the record but is only known once the
+        // whole value has been read, so it gets patched in afterwards
+        {
+            let mut file = BufWriter::new(&mut self.file);
+            let timestamp = checksum::now_millis();
+            RecordHeader { checksum: 0, key_len: key.len() as u32, value_len: len as u32, timestamp }.write(&mut file)?;
+            file.write_all(key)?;
+
+            let mut value = value.take(len);
+            let mut buf = [0; CATCH_UP_BUF_LEN];
+            let mut written = 0;
+            loop {
+                let n = value.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.write(&buf[..n]);
+                file.write_all(&buf[..n])?;
+                written += n as u64;
+            }
+            if written != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("expected {} bytes of value, got {}", len, written)
+                ));
+            }
+            file.flush()?;
+        }
+
+        // The store's own handle is in append mode, where positioned writes
+        // would land at the end of the file
+        let mut patch = OpenOptions::new().write(true).open(&self.path)?;
+        patch.seek(SeekFrom::Start(position))?;
+        patch.write_u64::<LittleEndian>(hasher.finish())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_seeks_and_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("stream.akv")).unwrap();
+        store.load().unwrap();
+        let value: ByteString = (0..200u32).map(|i| i as u8).collect();
+        store.insert_from_reader(b"plain", &value[..], 200).unwrap();
+        store.insert_chunked(b"chunked", &value[..], 64).unwrap();
+        assert_eq!(store.get(b"plain").unwrap(), Some(value.clone()));
+
+        for key in [&b"plain"[..], b"chunked"] {
+            let mut reader = store.get_reader(key).unwrap().unwrap();
+            assert_eq!(reader.len(), 200);
+            reader.seek(SeekFrom::Start(150)).unwrap();
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, &value[150..]);
+
+            reader.seek(SeekFrom::End(-190)).unwrap();
+            let mut middle = [0; 20];
+            reader.read_exact(&mut middle).unwrap();
+            assert_eq!(middle, &value[10..30]);
+        }
+    }
+
+    #[test]
+    fn reader_applies_merges() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("merged.akv")).unwrap();
+        store.load().unwrap();
+        store.insert(b"log", b"abc").unwrap();
+        store.merge(b"log", "append", b"def").unwrap();
+        store.merge(b"count", "u64_add", &3u64.to_le_bytes()).unwrap();
+
+        let mut reader = store.get_reader(b"log").unwrap().unwrap();
+        assert_eq!(reader.len(), 6);
+        reader.seek(SeekFrom::Start(2)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, b"cdef");
+
+        let mut count = Vec::new();
+        store.get_reader(b"count").unwrap().unwrap().read_to_end(&mut count).unwrap();
+        assert_eq!(count, 3u64.to_le_bytes());
+    }
+
+    #[test]
+    fn reader_reports_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corrupt.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"k", b"some value").unwrap();
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut reader = store.get_reader(b"k").unwrap().unwrap();
+        let mut value = Vec::new();
+        let err = reader.read_to_end(&mut value).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn short_reader_leaves_store_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("short.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let err = store.insert_from_reader(b"b", &b"abc"[..], 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+}
@@ -0,0 +1,196 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::{ActionKV, ByteString};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Turns typed keys and values into the bytes that `ActionKV` stores.
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<ByteString, BoxError>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError>;
+}
+
+/// The compact binary encoding `akv_disk` uses for its index.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<ByteString, BoxError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Human readable values, handy when other tools read the store too.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<ByteString, BoxError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// CBOR (RFC 8949), a self-describing binary encoding.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<ByteString, BoxError> {
+        let mut bytes = ByteString::new();
+        ciborium::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+/// Errors from a `TypedStore`.
+#[derive(Debug)]
+pub enum TypedError {
+    Io(io::Error),
+    /// A key or value couldn't be encoded
+    Encode(BoxError),
+    /// A stored value couldn't be decoded as the store's value type, most
+    /// likely because it was written with another type or codec
+    Decode { key: ByteString, source: BoxError },
+}
+
+impl fmt::Display for TypedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypedError::Io(err) => write!(f, "{}", err),
+            TypedError::Encode(err) => write!(f, "unable to encode: {}", err),
+            TypedError::Decode { key, source } => {
+                write!(f, "unable to decode the value of \"{}\": {}", crate::shell::escape(key), source)
+            },
+        }
+    }
+}
+
+impl Error for TypedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TypedError::Io(err) => Some(err),
+            TypedError::Encode(err) => Some(err.as_ref()),
+            TypedError::Decode { source, .. } => Some(source.as_ref()),
+        }
+    }
+}
+
+impl From<io::Error> for TypedError {
+    fn from(err: io::Error) -> TypedError {
+        TypedError::Io(err)
+    }
+}
+
+/// An `ActionKV` holding keys of type `K` and values of type `V`, converted
+/// to bytes with the codec `C`.
+///
+/// Deleted keys are stored as empty values like in `ActionKV`, so `V` should
+/// not be a type that `C` encodes as nothing, such as `()` with bincode.
+pub struct TypedStore<K, V, C = Bincode> {
+    store: ActionKV,
+    codec: C,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, C> TypedStore<K, V, C>
+where
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Wraps a loaded store.
+    pub fn new(store: ActionKV, codec: C) -> Self {
+        TypedStore { store, codec, types: PhantomData }
+    }
+
+    pub fn into_inner(self) -> ActionKV {
+        self.store
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, TypedError> {
+        let key = self.codec.encode(key).map_err(TypedError::Encode)?;
+        match self.store.get(&key)? {
+            None => Ok(None),
+            Some(value) if value.is_empty() => Ok(None),
+            Some(value) => {
+                let value = self.codec.decode(&value)
+                    .map_err(|source| TypedError::Decode { key, source })?;
+                Ok(Some(value))
+            },
+        }
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), TypedError> {
+        let key = self.codec.encode(key).map_err(TypedError::Encode)?;
+        let value = self.codec.encode(value).map_err(TypedError::Encode)?;
+        Ok(self.store.insert(&key, &value)?)
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<(), TypedError> {
+        let key = self.codec.encode(key).map_err(TypedError::Encode)?;
+        Ok(self.store.delete(&key)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        owner: String,
+        balance: i64,
+    }
+
+    fn round_trip<C: Codec>(name: &str, codec: C) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join(name)).unwrap();
+        store.load().unwrap();
+        let mut accounts: TypedStore<u32, Account, C> = TypedStore::new(store, codec);
+
+        let account = Account { owner: "ferris".to_string(), balance: 42 };
+        accounts.insert(&7, &account).unwrap();
+        assert_eq!(accounts.get(&7).unwrap(), Some(account));
+        assert_eq!(accounts.get(&8).unwrap(), None);
+        accounts.delete(&7).unwrap();
+        assert_eq!(accounts.get(&7).unwrap(), None);
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        round_trip("bincode.akv", Bincode);
+        round_trip("json.akv", Json);
+        round_trip("cbor.akv", Cbor);
+    }
+
+    #[test]
+    fn bad_values_are_decode_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("bad.akv")).unwrap();
+        store.load().unwrap();
+        store.insert(b"\"k\"", b"not json").unwrap();
+
+        let mut typed: TypedStore<String, Account, Json> = TypedStore::new(store, Json);
+        match typed.get(&"k".to_string()).unwrap_err() {
+            TypedError::Decode { key, .. } => assert_eq!(key, b"\"k\"".to_vec()),
+            err => panic!("expected a decode error, got {}", err),
+        }
+    }
+}
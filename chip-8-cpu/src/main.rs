@@ -1,14 +1,71 @@
 
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "
+Usage:
+    chip-8-cpu
+    chip-8-cpu run ROM_PATH
+
+With no command, runs the two built-in example programs.
+";
+
+const DISPLAY_WIDTH: usize = 64;
+const DISPLAY_HEIGHT: usize = 32;
+
+/// Where ROMs are loaded, as the memory below it held the interpreter itself
+const PROGRAM_START: usize = 0x200;
+/// Addresses are 12 bits, and wrap around past the end of memory
+const ADDRESS_MASK: usize = 0x0FFF;
+/// Roughly the speed of the original interpreters
+const INSTRUCTIONS_PER_SECOND: u32 = 700;
+const TIMER_HZ: u32 = 60;
+
+/// Sprites for the hex digits 0 to F, 5 bytes each, kept at the start of memory
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
 struct Chip8CPU {
     registers: [u8; 16],
+    // the I register, which holds memory addresses
+    index: u16,
     program_counter: usize,
     memory: [u8; 4096],
     stack: [u16; 16],
-    stack_pointer: usize
+    stack_pointer: usize,
+    // both timers count down at 60Hz, see tick_timers
+    delay_timer: u8,
+    sound_timer: u8,
+    display: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    // whether each of the 16 keys (0 to F) is held down
+    keys: [bool; 16],
+    // state of the xorshift generator used by CXNN
+    random_state: u32,
 }
 
 #[non_exhaustive]
 struct Registers;
+#[allow(dead_code)]
 impl Registers {
     pub const ONE: usize = 0;
     pub const TWO: usize = 1;
@@ -29,62 +86,205 @@ impl Registers {
 }
 
 impl Chip8CPU {
+    /// A CPU with everything zeroed except the font sprites at 0x000
+    fn new() -> Chip8CPU {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+        let mut cpu = Chip8CPU {
+            registers: [0; 16],
+            index: 0,
+            program_counter: 0,
+            memory: [0; 4096],
+            stack: [0; 16],
+            stack_pointer: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            display: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            keys: [false; 16],
+            // xorshift never leaves zero, so keep the state odd
+            random_state: seed | 1,
+        };
+        cpu.memory[..FONT.len()].copy_from_slice(&FONT);
+        cpu
+    }
+
+    /// A CPU ready to run `rom`, which is copied into memory at
+    /// PROGRAM_START
+    fn with_rom(rom: &[u8]) -> io::Result<Chip8CPU> {
+        let mut cpu = Chip8CPU::new();
+        let space = cpu.memory.len() - PROGRAM_START;
+        if rom.len() > space {
+            let message = format!("ROM is {} bytes, but only {} fit in memory", rom.len(), space);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        cpu.memory[PROGRAM_START..PROGRAM_START + rom.len()].copy_from_slice(rom);
+        cpu.program_counter = PROGRAM_START;
+        Ok(cpu)
+    }
+
     fn read_opcode(&self) -> u16 {
         // CHIP-8 opcodes are u16 values made up of 4 nibbles (half a byte)
         let pc = self.program_counter;
-        let op_byte1 = self.memory[pc] as u16;
+        let op_byte1 = self.memory[pc & ADDRESS_MASK] as u16;
         // since memory is a list of u8 elements
-        let op_byte2 = self.memory[pc+1] as u16;
+        let op_byte2 = self.memory[(pc + 1) & ADDRESS_MASK] as u16;
 
         // combine both u8 opcode bytes to create the opcode
         // since opcode is 0x0000 = 0x[op_byte1][op_byte2]
         op_byte1 << 8 | op_byte2
     }
 
-    fn run(&mut self) {
-        loop {
-            let opcode: u16 = self.read_opcode();
-            self.program_counter += 2;
-            // opcode is represented as 16 bit hexadecimal value such as 0x1234
-            // as mentioned in read_opcode, the opcodes consist of 4 nibbles
-            // the first nibble is the opcode group
-            // the second nibble is the first register for the instruction
-            // the third nibble is the second register for the instruction
-            // the fourth nibble is the opcode sub group
-            // the & operation is to ensure that all of the other bits for the other nibbles
-            // are cleared and only the bits for the specific variable remain set
-            let opcode_group = ((opcode & 0xF000) >> 12) as u8;
-            let register1 = ((opcode & 0x0F00) >> 8) as u8;
-            let register2 = ((opcode & 0x00F0) >> 4) as u8;
-            let opcode_sub_group = ((opcode & 0x000F) >> 0) as u8;
-
-            match (opcode_group, register1, register2, opcode_sub_group) {
-                (0, 0, 0, 0) => break,
-                (0, 0 , 0xE, 0xE) => {
-                    if self.stack_pointer == 0 {
-                        panic!("Stack Underflow")
-                    }
-                    self.stack_pointer -= 1;
-                    let previous_memory_address = self.stack[self.stack_pointer];
-                    self.program_counter = previous_memory_address as usize;
-                },
-                (8, _, _, 4) => self.add_xy(register1, register2),
-                (2, _, _, _) => {
-                    if self.stack_pointer > self.stack.len() {
-                        panic!("Stack Overflow")
-                    }
+    fn run(&mut self) -> io::Result<()> {
+        while self.step()? {}
+        Ok(())
+    }
 
-                    // store the current memory location on the stack
-                    self.stack[self.stack_pointer] = self.program_counter as u16;
-                    // increment stack pointer
-                    self.stack_pointer += 1;
-                    // set the current memory location to intended memory address `nnn`
-                    // Set program counter to nnn for opcode 0x2nnn
-                    let address = opcode & 0x0FFF;
-                    self.program_counter = address as usize;
-                },
-                _  =>  todo!("opcode {:04x}", opcode)
-            }
+    /// Runs a single instruction, returning false once the program has ended.
+    /// Unknown opcodes and calls or returns past the ends of the stack are
+    /// errors.
+    fn step(&mut self) -> io::Result<bool> {
+        let opcode: u16 = self.read_opcode();
+        let at = self.program_counter;
+        self.jump(at + 2);
+        // opcode is represented as 16 bit hexadecimal value such as 0x1234
+        // as mentioned in read_opcode, the opcodes consist of 4 nibbles
+        // the first nibble is the opcode group
+        // the second nibble is the first register for the instruction
+        // the third nibble is the second register for the instruction
+        // the fourth nibble is the opcode sub group
+        // the & operation is to ensure that all of the other bits for the other nibbles
+        // are cleared and only the bits for the specific variable remain set
+        let opcode_group = ((opcode & 0xF000) >> 12) as u8;
+        let register1 = ((opcode & 0x0F00) >> 8) as u8;
+        let register2 = ((opcode & 0x00F0) >> 4) as u8;
+        let opcode_sub_group = (opcode & 0x000F) as u8;
+        // many instructions take an address (nnn) or a byte (nn) in place of
+        // the last nibbles
+        let address = opcode & 0x0FFF;
+        let byte = (opcode & 0x00FF) as u8;
+
+        let x = register1 as usize;
+        let y = register2 as usize;
+
+        match (opcode_group, register1, register2, opcode_sub_group) {
+            (0, 0, 0, 0) => return Ok(false),
+            (0, 0, 0xE, 0) => self.display = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            (0, 0 , 0xE, 0xE) => {
+                if self.stack_pointer == 0 {
+                    return Err(fault("stack underflow", at));
+                }
+                self.stack_pointer -= 1;
+                let previous_memory_address = self.stack[self.stack_pointer];
+                self.jump(previous_memory_address as usize);
+            },
+            // 0nnn calls machine code on the original hardware, which
+            // emulators ignore
+            (0, _, _, _) => {},
+            (1, _, _, _) => self.jump(address as usize),
+            (2, _, _, _) => {
+                if self.stack_pointer >= self.stack.len() {
+                    return Err(fault("stack overflow", at));
+                }
+
+                // store the current memory location on the stack
+                self.stack[self.stack_pointer] = self.program_counter as u16;
+                // increment stack pointer
+                self.stack_pointer += 1;
+                // set the current memory location to intended memory address `nnn`
+                // Set program counter to nnn for opcode 0x2nnn
+                self.jump(address as usize);
+            },
+            (3, _, _, _) => self.skip_if(self.registers[x] == byte),
+            (4, _, _, _) => self.skip_if(self.registers[x] != byte),
+            (5, _, _, 0) => self.skip_if(self.registers[x] == self.registers[y]),
+            (6, _, _, _) => self.registers[x] = byte,
+            // adds without touching the carry flag
+            (7, _, _, _) => self.registers[x] = self.registers[x].wrapping_add(byte),
+            (8, _, _, 0) => self.registers[x] = self.registers[y],
+            (8, _, _, 1) => self.registers[x] |= self.registers[y],
+            (8, _, _, 2) => self.registers[x] &= self.registers[y],
+            (8, _, _, 3) => self.registers[x] ^= self.registers[y],
+            (8, _, _, 4) => self.add_xy(register1, register2),
+            (8, _, _, 5) => self.sub_xy(register1, register1, register2),
+            // shifts work on vx in place, as most later interpreters do,
+            // and leave the bit shifted out in vf
+            (8, _, _, 6) => {
+                let value = self.registers[x];
+                self.registers[x] = value >> 1;
+                self.registers[0xf] = value & 1;
+            },
+            (8, _, _, 7) => self.sub_xy(register1, register2, register1),
+            (8, _, _, 0xE) => {
+                let value = self.registers[x];
+                self.registers[x] = value << 1;
+                self.registers[0xf] = value >> 7;
+            },
+            (9, _, _, 0) => self.skip_if(self.registers[x] != self.registers[y]),
+            (0xA, _, _, _) => self.index = address,
+            (0xB, _, _, _) => self.jump(address as usize + self.registers[0] as usize),
+            (0xC, _, _, _) => self.registers[x] = self.random_byte() & byte,
+            (0xD, _, _, _) => self.draw(register1, register2, opcode_sub_group),
+            (0xE, _, 9, 0xE) => self.skip_if(self.keys[(self.registers[x] & 0xF) as usize]),
+            (0xE, _, 0xA, 1) => self.skip_if(!self.keys[(self.registers[x] & 0xF) as usize]),
+            (0xF, _, 0, 7) => self.registers[x] = self.delay_timer,
+            (0xF, _, 0, 0xA) => match self.keys.iter().position(|&pressed| pressed) {
+                Some(key) => self.registers[x] = key as u8,
+                // run this instruction again until a key is pressed
+                None => self.jump(at),
+            },
+            (0xF, _, 1, 5) => self.delay_timer = self.registers[x],
+            (0xF, _, 1, 8) => self.sound_timer = self.registers[x],
+            (0xF, _, 1, 0xE) => self.index = self.memory_address(self.registers[x] as usize) as u16,
+            // each font sprite is 5 bytes long
+            (0xF, _, 2, 9) => self.index = (self.registers[x] & 0xF) as u16 * 5,
+            (0xF, _, 3, 3) => {
+                // binary-coded decimal: hundreds, tens then ones
+                let value = self.registers[x];
+                let digits = [value / 100, value / 10 % 10, value % 10];
+                for (offset, &digit) in digits.iter().enumerate() {
+                    self.memory[self.memory_address(offset)] = digit;
+                }
+            },
+            // store and load registers 0 to x at I, leaving I as it is
+            (0xF, _, 5, 5) => {
+                for offset in 0..=x {
+                    self.memory[self.memory_address(offset)] = self.registers[offset];
+                }
+            },
+            (0xF, _, 6, 5) => {
+                for offset in 0..=x {
+                    self.registers[offset] = self.memory[self.memory_address(offset)];
+                }
+            },
+            _ => return Err(fault(&format!("unknown opcode {:04x}", opcode), at)),
+        }
+        Ok(true)
+    }
+
+    /// Points the program counter at `address`, wrapped to 12 bits
+    fn jump(&mut self, address: usize) {
+        self.program_counter = address & ADDRESS_MASK;
+    }
+
+    /// The address `offset` bytes past I, wrapped to 12 bits
+    fn memory_address(&self, offset: usize) -> usize {
+        (self.index as usize + offset) & ADDRESS_MASK
+    }
+
+    /// Counts the timers down by one, to be called 60 times a second
+    fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    /// Whether the next instruction jumps to itself, which is how most ROMs
+    /// stop once they are done
+    fn is_stuck(&self) -> bool {
+        self.read_opcode() == 0x1000 | self.program_counter as u16
+    }
+
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.jump(self.program_counter + 2);
         }
     }
 
@@ -101,25 +301,145 @@ impl Chip8CPU {
         }
     }
 
+    /// Stores register a - register b in register `target`, setting vf to 1
+    /// when there is no borrow
+    fn sub_xy(&mut self, target: u8, a: u8, b: u8) {
+        let a_value = self.registers[a as usize];
+        let b_value = self.registers[b as usize];
+        let (val, borrow) = a_value.overflowing_sub(b_value);
+        self.registers[target as usize] = val;
+        self.registers[0xf] = if borrow { 0 } else { 1 };
+    }
+
+    /// XORs the n byte sprite at I onto the display at (vx, vy), setting vf
+    /// to 1 if any lit pixel was turned off
+    fn draw(&mut self, x: u8, y: u8, n: u8) {
+        // the start position wraps around the screen, but the sprite is
+        // clipped at the edges
+        let left = self.registers[x as usize] as usize % DISPLAY_WIDTH;
+        let top = self.registers[y as usize] as usize % DISPLAY_HEIGHT;
+        self.registers[0xf] = 0;
+        for row in 0..n as usize {
+            let py = top + row;
+            if py >= DISPLAY_HEIGHT {
+                break;
+            }
+            let sprite_byte = self.memory[self.memory_address(row)];
+            for column in 0..8 {
+                let px = left + column;
+                if px >= DISPLAY_WIDTH {
+                    break;
+                }
+                if sprite_byte & (0x80 >> column) != 0 {
+                    if self.display[py][px] {
+                        self.registers[0xf] = 1;
+                    }
+                    self.display[py][px] ^= true;
+                }
+            }
+        }
+    }
 
+    fn random_byte(&mut self) -> u8 {
+        let mut state = self.random_state;
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        self.random_state = state;
+        (state >> 24) as u8
+    }
+}
+
+/// An instruction at `at` that the CPU can't carry out
+fn fault(message: &str, at: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} at {:03x}", message, at))
 }
 
 fn main() {
-    println!("Beep boop! Running CPU...");
-    run_program_one();
-    run_program_two();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        None => {
+            println!("Beep boop! Running CPU...");
+            run_program_one();
+            run_program_two();
+        },
+        Some("run") => {
+            let rom_path = args.get(2).expect(USAGE);
+            if let Err(err) = run_rom(Path::new(rom_path)) {
+                eprintln!("Unable to run {}: {}", rom_path, err);
+                std::process::exit(1);
+            }
+        },
+        Some(_) => {
+            eprintln!("{}", USAGE.trim());
+            std::process::exit(1);
+        },
+    }
+}
+
+fn load_rom(path: &Path) -> io::Result<Chip8CPU> {
+    let rom = fs::read(path)?;
+    Chip8CPU::with_rom(&rom)
+}
+
+/// Runs the ROM at `path` in real time, drawing the display in the
+/// terminal, until it ends or jumps to itself. There is no keyboard input,
+/// so ROMs waiting on a key wait forever. An instruction the CPU can't carry
+/// out ends the run with an `InvalidData` error, as a bad ROM file would.
+fn run_rom(path: &Path) -> io::Result<()> {
+    let mut cpu = load_rom(path)?;
+    let instructions_per_tick = INSTRUCTIONS_PER_SECOND / TIMER_HZ;
+    let tick = Duration::from_secs(1) / TIMER_HZ;
+    let mut drawn = None;
+    let mut next_tick = Instant::now();
+
+    loop {
+        for _ in 0..instructions_per_tick {
+            if cpu.is_stuck() || !cpu.step()? {
+                draw_display(&cpu.display)?;
+                return Ok(());
+            }
+        }
+        cpu.tick_timers();
+        if drawn != Some(cpu.display) {
+            draw_display(&cpu.display)?;
+            drawn = Some(cpu.display);
+        }
+
+        next_tick += tick;
+        thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn draw_display(display: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT]) -> io::Result<()> {
+    let mut frame = String::new();
+    // clear the terminal and move the cursor to the top left
+    frame.push_str("\x1B[2J\x1B[H");
+    for row in display {
+        frame.extend(row.iter().map(|&pixel| if pixel { '█' } else { ' ' }));
+        frame.push('\n');
+    }
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(frame.as_bytes())?;
+    stdout.flush()
 }
 
 
 fn run_program_one() {
     println!("Running Program ONE...");
-    let mut cpu = Chip8CPU {
-        registers: [0; 16],
-        program_counter: 0,
-        memory: [0; 4096],
-        stack_pointer: 0,
-        stack: [0; 16]
-    };
+    let cpu = program_one();
+
+    // print value of registers
+    println!("Finished calculation!");
+    println!("Register 1: {}", cpu.registers[Registers::ONE]);
+    println!("Register 2: {}", cpu.registers[Registers::TWO]);
+    println!("Register 3: {}", cpu.registers[Registers::THREE]);
+    println!("Register 4: {}", cpu.registers[Registers::FOUR]);
+}
+
+/// Adds registers 2, 3 and 4 to register 1, returning the CPU once it halts
+fn program_one() -> Chip8CPU {
+    let mut cpu = Chip8CPU::new();
 
     // Load values into registers 1, 2, 3 and 4
     cpu.registers[Registers::ONE] = 0;
@@ -135,26 +455,23 @@ fn run_program_one() {
     memory[2] = 0x80; memory[3] = 0x24;
     // Load 0x8034 instruction - add register 4 to register 1
     memory[4] = 0x80; memory[5] = 0x34;
+    // opcode 0000 finish program, rather than running on into the font
+    memory[6] = 0x00; memory[7] = 0x00;
 
-    cpu.run();
-
-    // print value of registers
-    println!("Finished calculation!");
-    println!("Register 1: {}", cpu.registers[Registers::ONE]);
-    println!("Register 2: {}", cpu.registers[Registers::TWO]);
-    println!("Register 3: {}", cpu.registers[Registers::THREE]);
-    println!("Register 4: {}", cpu.registers[Registers::FOUR]);
+    cpu.run().expect("Program ONE failed");
+    cpu
 }
 
 fn run_program_two() {
     println!("Running Program TWO...");
-    let mut cpu = Chip8CPU {
-        registers: [0; 16],
-        program_counter: 0,
-        memory: [0; 4096],
-        stack_pointer: 0,
-        stack: [0; 16]
-    };
+    let cpu = program_two();
+    println!("5 + (10 * 2) + (10 * 2) = {}", cpu.registers[0]);
+}
+
+/// Calls a function that adds register 2 to register 1 twice, twice,
+/// returning the CPU once it halts
+fn program_two() -> Chip8CPU {
+    let mut cpu = Chip8CPU::new();
 
     cpu.registers[Registers::ONE] = 5;
     cpu.registers[Registers::TWO] = 10;
@@ -176,7 +493,437 @@ fn run_program_two() {
     // return to function call
     memory[0x104] = 0x00; memory[0x105] = 0xEE;
 
-    cpu.run();
+    cpu.run().expect("Program TWO failed");
+    cpu
+}
 
-    println!("5 + (10 * 2) + (10 * 2) = {}", cpu.registers[0]);
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CPU with `program` loaded at 0x200, followed by zeroed memory that
+    /// halts it
+    fn cpu_with(program: &[u16]) -> Chip8CPU {
+        let mut cpu = Chip8CPU::new();
+        for (i, opcode) in program.iter().enumerate() {
+            let at = 0x200 + i * 2;
+            cpu.memory[at..at + 2].copy_from_slice(&opcode.to_be_bytes());
+        }
+        cpu.program_counter = 0x200;
+        cpu
+    }
+
+    #[test]
+    fn rom_is_loaded_after_the_font() {
+        let cpu = Chip8CPU::with_rom(&[0x60, 0x01]).unwrap();
+        assert_eq!(cpu.program_counter, PROGRAM_START);
+        assert_eq!(cpu.memory[PROGRAM_START..PROGRAM_START + 3], [0x60, 0x01, 0]);
+        assert_eq!(cpu.memory[..FONT.len()], FONT);
+
+        assert!(Chip8CPU::with_rom(&[0xFF; 4096 - PROGRAM_START]).is_ok());
+        let err = Chip8CPU::with_rom(&[0xFF; 4096 - PROGRAM_START + 1]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn built_in_programs_halt_with_their_results() {
+        assert_eq!(program_one().registers[Registers::ONE], 38);
+        assert_eq!(program_two().registers[Registers::ONE], 45);
+    }
+
+    #[test]
+    fn missing_rom_is_an_error() {
+        let err = load_rom(Path::new("no/such/rom.ch8")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn faulting_rom_is_an_error() {
+        let path = std::env::temp_dir().join(format!("chip-8-cpu-{}.ch8", std::process::id()));
+        // v0 = 1, then an opcode that doesn't exist
+        fs::write(&path, [0x60, 0x01, 0x50, 0x01]).unwrap();
+        let result = run_rom(&path);
+        fs::remove_file(&path).unwrap();
+
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "unknown opcode 5001 at 202");
+    }
+
+    #[test]
+    fn timers_count_down_to_zero() {
+        let mut cpu = Chip8CPU::new();
+        cpu.delay_timer = 2;
+        cpu.sound_timer = 1;
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (1, 0));
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (0, 0));
+    }
+
+    #[test]
+    fn halt_0000() {
+        let mut cpu = cpu_with(&[]);
+        assert!(!cpu.step().unwrap());
+        assert_eq!(cpu.program_counter, 0x202);
+    }
+
+    #[test]
+    fn sys_0nnn_is_ignored() {
+        let mut cpu = cpu_with(&[0x0123, 0x6005]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0], 5);
+    }
+
+    #[test]
+    fn clear_screen_00e0() {
+        let mut cpu = cpu_with(&[0x00E0]);
+        cpu.display[3][4] = true;
+        cpu.run().unwrap();
+        assert!(cpu.display.iter().flatten().all(|&pixel| !pixel));
+    }
+
+    #[test]
+    fn call_2nnn_and_return_00ee() {
+        // call 0x300, which sets v0 and returns to set v1
+        let mut cpu = cpu_with(&[0x2300, 0x6102]);
+        cpu.memory[0x300..0x304].copy_from_slice(&[0x60, 0x01, 0x00, 0xEE]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[..2], [1, 2]);
+        assert_eq!(cpu.stack_pointer, 0);
+    }
+
+    #[test]
+    fn stack_overflow_and_underflow_are_errors() {
+        // calls itself forever
+        let err = cpu_with(&[0x2200]).run().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "stack overflow at 200");
+
+        let err = cpu_with(&[0x6001, 0x00EE]).run().unwrap_err();
+        assert_eq!(err.to_string(), "stack underflow at 202");
+    }
+
+    #[test]
+    fn unknown_opcodes_are_errors() {
+        let err = cpu_with(&[0x5001]).run().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "unknown opcode 5001 at 200");
+    }
+
+    #[test]
+    fn jump_1nnn() {
+        let mut cpu = cpu_with(&[0x1206, 0x6001, 0x0000, 0x6102]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[..2], [0, 2]);
+    }
+
+    #[test]
+    fn skip_if_equal_3xnn() {
+        let mut cpu = cpu_with(&[0x3000, 0x6101, 0x3001, 0x6202]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1..3], [0, 2]);
+    }
+
+    #[test]
+    fn skip_if_not_equal_4xnn() {
+        let mut cpu = cpu_with(&[0x4001, 0x6101, 0x4000, 0x6202]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1..3], [0, 2]);
+    }
+
+    #[test]
+    fn skip_if_registers_equal_5xy0() {
+        let mut cpu = cpu_with(&[0x6201, 0x5010, 0x6101, 0x5120, 0x6302]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[1], cpu.registers[3]), (0, 2));
+    }
+
+    #[test]
+    fn load_6xnn() {
+        let mut cpu = cpu_with(&[0x6A42]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0xA], 0x42);
+    }
+
+    #[test]
+    fn add_7xnn_wraps_without_carry() {
+        let mut cpu = cpu_with(&[0x60FF, 0x7002, 0x7103]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[..2], [1, 3]);
+        assert_eq!(cpu.registers[0xf], 0);
+    }
+
+    #[test]
+    fn copy_8xy0() {
+        let mut cpu = cpu_with(&[0x6107, 0x8010]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0], 7);
+    }
+
+    #[test]
+    fn or_8xy1() {
+        let mut cpu = cpu_with(&[0x600C, 0x610A, 0x8011]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0], 0x0E);
+    }
+
+    #[test]
+    fn and_8xy2() {
+        let mut cpu = cpu_with(&[0x600C, 0x610A, 0x8012]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0], 0x08);
+    }
+
+    #[test]
+    fn xor_8xy3() {
+        let mut cpu = cpu_with(&[0x600C, 0x610A, 0x8013]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0], 0x06);
+    }
+
+    #[test]
+    fn add_8xy4_sets_carry() {
+        let mut cpu = cpu_with(&[0x60F0, 0x6120, 0x8014]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (0x10, 1));
+
+        let mut cpu = cpu_with(&[0x6010, 0x6120, 0x8014]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (0x30, 0));
+    }
+
+    #[test]
+    fn sub_8xy5_sets_not_borrow() {
+        let mut cpu = cpu_with(&[0x6005, 0x6103, 0x8015]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (2, 1));
+
+        let mut cpu = cpu_with(&[0x6003, 0x6105, 0x8015]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (0xFE, 0));
+    }
+
+    #[test]
+    fn shift_right_8xy6() {
+        let mut cpu = cpu_with(&[0x6005, 0x8006]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (2, 1));
+
+        let mut cpu = cpu_with(&[0x6004, 0x8006]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (2, 0));
+    }
+
+    #[test]
+    fn reverse_sub_8xy7() {
+        let mut cpu = cpu_with(&[0x6003, 0x6105, 0x8017]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (2, 1));
+
+        let mut cpu = cpu_with(&[0x6005, 0x6103, 0x8017]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (0xFE, 0));
+    }
+
+    #[test]
+    fn shift_left_8xye() {
+        let mut cpu = cpu_with(&[0x6081, 0x800E]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (2, 1));
+
+        let mut cpu = cpu_with(&[0x6041, 0x800E]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[0xf]), (0x82, 0));
+    }
+
+    #[test]
+    fn flag_wins_when_vf_is_the_target() {
+        let mut cpu = cpu_with(&[0x6FFF, 0x6101, 0x8F14]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0xf], 1);
+    }
+
+    #[test]
+    fn skip_if_registers_differ_9xy0() {
+        let mut cpu = cpu_with(&[0x6201, 0x9010, 0x6101, 0x9320, 0x6402]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[1], cpu.registers[4]), (1, 0));
+    }
+
+    #[test]
+    fn set_index_annn() {
+        let mut cpu = cpu_with(&[0xA123]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.index, 0x123);
+    }
+
+    #[test]
+    fn jump_plus_v0_bnnn() {
+        let mut cpu = cpu_with(&[0x6004, 0xB204, 0x6101, 0x0000, 0x6202]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1..3], [0, 2]);
+    }
+
+    #[test]
+    fn random_cxnn_is_masked() {
+        let mut cpu = cpu_with(&[0x60FF, 0xC000, 0xC10F]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(cpu.registers[1] & 0xF0, 0);
+    }
+
+    #[test]
+    fn draw_dxyn_xors_and_reports_collisions() {
+        // the "0" font sprite at (1, 2)
+        let mut cpu = cpu_with(&[0x6001, 0x6102, 0xA000, 0xD015]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0xf], 0);
+        assert!(cpu.display[2][1..5].iter().all(|&pixel| pixel));
+        assert!(!cpu.display[3][2] && cpu.display[3][4]);
+
+        // drawing it again erases it
+        cpu.program_counter = 0x206;
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0xf], 1);
+        assert!(cpu.display.iter().flatten().all(|&pixel| !pixel));
+    }
+
+    #[test]
+    fn draw_dxyn_wraps_start_and_clips_edges() {
+        // x = 66 wraps to 2; y = 30 leaves room for two rows only
+        let mut cpu = cpu_with(&[0x6042, 0x611E, 0xA000, 0xD015]);
+        cpu.run().unwrap();
+        assert!(cpu.display[30][2] && cpu.display[31][2]);
+        assert!(!cpu.display[0][2]);
+
+        // x = 62 clips the right half of the sprite
+        let mut cpu = cpu_with(&[0x603E, 0x6100, 0xA000, 0xD011]);
+        cpu.run().unwrap();
+        assert!(cpu.display[0][62] && cpu.display[0][63]);
+        assert!(!cpu.display[0][0]);
+    }
+
+    #[test]
+    fn skip_if_key_ex9e() {
+        let mut cpu = cpu_with(&[0x6005, 0xE09E, 0x6101, 0xE19E, 0x6202]);
+        cpu.keys[5] = true;
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1..3], [0, 2]);
+    }
+
+    #[test]
+    fn skip_if_not_key_exa1() {
+        let mut cpu = cpu_with(&[0x6005, 0xE0A1, 0x6101, 0xE1A1, 0x6202]);
+        cpu.keys[5] = true;
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1..3], [1, 0]);
+    }
+
+    #[test]
+    fn read_delay_timer_fx07() {
+        let mut cpu = cpu_with(&[0xF307]);
+        cpu.delay_timer = 9;
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[3], 9);
+    }
+
+    #[test]
+    fn wait_for_key_fx0a() {
+        let mut cpu = cpu_with(&[0xF30A]);
+        assert!(cpu.step().unwrap());
+        assert!(cpu.step().unwrap());
+        assert_eq!(cpu.program_counter, 0x200);
+
+        cpu.keys[0xB] = true;
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[3], 0xB);
+    }
+
+    #[test]
+    fn set_delay_timer_fx15() {
+        let mut cpu = cpu_with(&[0x6302, 0xF315]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.delay_timer, 2);
+    }
+
+    #[test]
+    fn set_sound_timer_fx18() {
+        let mut cpu = cpu_with(&[0x6302, 0xF318]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.sound_timer, 2);
+    }
+
+    #[test]
+    fn add_to_index_fx1e() {
+        let mut cpu = cpu_with(&[0xA100, 0x6310, 0xF31E]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.index, 0x110);
+        assert_eq!(cpu.registers[0xf], 0);
+    }
+
+    #[test]
+    fn font_sprite_fx29() {
+        let mut cpu = cpu_with(&[0x630A, 0xF329]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.index, 50);
+        assert_eq!(cpu.memory[50..55], [0xF0, 0x90, 0xF0, 0x90, 0x90]);
+    }
+
+    #[test]
+    fn decimal_digits_fx33() {
+        let mut cpu = cpu_with(&[0x63FE, 0xA300, 0xF333]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory[0x300..0x303], [2, 5, 4]);
+    }
+
+    #[test]
+    fn store_registers_fx55() {
+        let mut cpu = cpu_with(&[0x6001, 0x6102, 0x6203, 0x6304, 0xA300, 0xF255]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory[0x300..0x304], [1, 2, 3, 0]);
+        assert_eq!(cpu.index, 0x300);
+    }
+
+    #[test]
+    fn load_registers_fx65() {
+        let mut cpu = cpu_with(&[0xA300, 0xF165]);
+        cpu.memory[0x300..0x303].copy_from_slice(&[7, 8, 9]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[..3], [7, 8, 0]);
+        assert_eq!(cpu.index, 0x300);
+    }
+
+    #[test]
+    fn addresses_wrap_at_4k() {
+        // I + vx wraps past 0xFFF
+        let mut cpu = cpu_with(&[0xAFFF, 0x6302, 0xF31E]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.index, 0x001);
+
+        // registers are stored across the end of memory
+        let mut cpu = cpu_with(&[0x6001, 0x6102, 0x6203, 0xAFFE, 0xF255]);
+        cpu.run().unwrap();
+        assert_eq!([cpu.memory[0xFFE], cpu.memory[0xFFF], cpu.memory[0]], [1, 2, 3]);
+
+        // the digits of 254 too, and loaded back
+        let mut cpu = cpu_with(&[0x60FE, 0xAFFF, 0xF033, 0xF265]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[..3], [2, 5, 4]);
+
+        // the sprite's second row comes from 0x000
+        let mut cpu = cpu_with(&[0xAFFF, 0xD002]);
+        cpu.memory[0xFFF] = 0x80;
+        cpu.memory[0] = 0x40;
+        cpu.run().unwrap();
+        assert!(cpu.display[0][0] && cpu.display[1][1]);
+    }
+
+    #[test]
+    fn jump_plus_v0_bnnn_wraps() {
+        let mut cpu = cpu_with(&[0x60FF, 0xBFFF]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0FE);
+    }
+}